@@ -0,0 +1,159 @@
+use failure::Error;
+use std::path::{Path, PathBuf};
+
+/// Directory holding the screenshots and page snapshots of a user's pins
+pub fn cache_directory(username: &str) -> PathBuf {
+    Path::new("cache").join(username)
+}
+
+/// Directory cached files are moved to while their pin is in the trash
+pub fn trash_cache_directory(username: &str) -> PathBuf {
+    Path::new("trash").join(username).join("cache")
+}
+
+/// Name shared by the cached files of one of a pin's urls. Files of the first url are named after
+/// the pin alone, as they were before pins could have several urls.
+pub fn url_basename(id: &str, url_index: usize) -> String {
+    if url_index == 0 {
+        id.to_string()
+    } else {
+        format!("{}_{}", id, url_index)
+    }
+}
+
+/// Splits the name of a cached file into the pin it belongs to, the index of the url it was
+/// downloaded from and its extension, e.g. `{id}_2.html` into `({id}, 2, "html")`
+pub fn parse_cached_filename(filename: &str) -> Option<(&str, usize, &str)> {
+    let (stem, extension) = filename.split_once('.')?;
+    match stem.split_once('_') {
+        None => Some((stem, 0, extension)),
+        Some((id, index)) => Some((id, index.parse().ok()?, extension)),
+    }
+}
+
+pub fn has_cached_page(username: &str, id: &str) -> bool {
+    cache_directory(username)
+        .join(format!("{}.html", id))
+        .exists()
+}
+
+/// Icon of a pin's site, scaled down and converted to png
+pub fn favicon_filename(username: &str, id: &str) -> PathBuf {
+    cache_directory(username).join(format!("{}.favicon.png", id))
+}
+
+pub fn has_favicon(username: &str, id: &str) -> bool {
+    favicon_filename(username, id).exists()
+}
+
+/// Every file in `directory` belonging to the pin `id`, i.e. named `{id}.*` or `{id}_*`
+fn cached_files(directory: &Path, id: &str) -> Result<Vec<PathBuf>, Error> {
+    if !directory.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut result = vec![];
+    for file in std::fs::read_dir(directory)? {
+        let path = match file {
+            Err(_) => continue,
+            Ok(x) => x.path(),
+        };
+
+        let belongs_to_pin = match path.file_name().and_then(|f| f.to_str()) {
+            None => false,
+            Some(filename) => {
+                filename.starts_with(&format!("{}.", id))
+                    || filename.starts_with(&format!("{}_", id))
+            }
+        };
+
+        if belongs_to_pin {
+            result.push(path);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Files of a pin's urls, along with the index of the url each one was downloaded from
+fn url_files(username: &str, id: &str) -> Result<Vec<(usize, PathBuf)>, Error> {
+    let mut result = vec![];
+    for path in cached_files(&cache_directory(username), id)? {
+        let parsed = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(parse_cached_filename)
+            .map(|(pin_id, index, _)| (pin_id == id, index));
+
+        if let Some((true, index)) = parsed {
+            result.push((index, path));
+        }
+    }
+
+    result.sort();
+    Ok(result)
+}
+
+/// Cached pages of a pin, one for each of its urls that got downloaded, first url first
+pub fn cached_pages(username: &str, id: &str) -> Result<Vec<PathBuf>, Error> {
+    Ok(url_files(username, id)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .filter(|path| path.extension().is_some_and(|e| e == "html"))
+        .collect())
+}
+
+/// Removes the files of urls a pin doesn't have anymore. Files of the first url are kept even
+/// when the pin has none left, as they hold its favicon and snapshots too.
+pub fn remove_extra_url_files(username: &str, id: &str, url_count: usize) -> Result<(), Error> {
+    for (index, path) in url_files(username, id)? {
+        if index > 0 && index >= url_count {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the cached page, thumbnail and WARC file of one pin's url to another pin's url, like
+/// when the pins are merged
+pub fn move_url_files(username: &str, from: (&str, usize), to: (&str, usize)) -> Result<(), Error> {
+    let directory = cache_directory(username);
+    for extension in &["html", "jpg", "warc"] {
+        let from_file = directory.join(format!("{}.{}", url_basename(from.0, from.1), extension));
+        if from_file.exists() {
+            std::fs::rename(
+                from_file,
+                directory.join(format!("{}.{}", url_basename(to.0, to.1), extension)),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn move_cached_files(id: &str, from: &Path, to: &Path) -> Result<(), Error> {
+    let files = cached_files(from, id)?;
+
+    if !files.is_empty() {
+        std::fs::create_dir_all(to)?;
+    }
+
+    for file in files {
+        std::fs::rename(&file, to.join(file.file_name().unwrap()))?;
+    }
+
+    Ok(())
+}
+
+pub fn remove_cached_files(id: &str, directory: &Path) -> Result<(), Error> {
+    for file in cached_files(directory, id)? {
+        if file.is_dir() {
+            std::fs::remove_dir_all(file)?;
+        } else {
+            std::fs::remove_file(file)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use failure::Error;
+use serde::Deserialize;
+
+use crate::page_renderer::RendererChoice;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Json,
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub storage: StorageBackend,
+    pub sqlite_path: String,
+    /// Days a deleted pin stays in the trash before being deleted permanently
+    pub trash_retention_days: i64,
+    /// Number of pages downloaded at the same time
+    pub download_workers: usize,
+    /// Pages from a single host downloaded at the same time, so one bulk import doesn't hold up
+    /// every other download
+    pub downloads_per_host: usize,
+    /// Seconds a browser gets to take a screenshot or dump a page before it's killed
+    pub browser_timeout_seconds: u64,
+    /// Biggest asset, in bytes, embedded into a cached page
+    pub archive_max_asset_size: usize,
+    /// Bytes of assets downloaded to embed into a single cached page
+    pub archive_max_page_size: usize,
+    /// Seconds spent downloading the assets of a single page before leaving the rest out
+    pub archive_inline_timeout_seconds: u64,
+    /// Also save the http responses of every downloaded page in a WARC file
+    pub archive_warc: bool,
+    /// Seconds between the downloads of pins added by an import, so it doesn't flood the queue
+    pub import_download_interval_seconds: i64,
+    /// Days between checks of whether a pin's url still works, or 0 to never check
+    pub link_check_interval_days: i64,
+    /// Browser used to take screenshots and cache pages
+    pub renderer: RendererChoice,
+    /// Directory the fake renderer serves its pages and screenshots from
+    pub renderer_fixtures: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            storage: StorageBackend::Json,
+            sqlite_path: String::from("pins.sqlite"),
+            trash_retention_days: 30,
+            download_workers: 4,
+            downloads_per_host: 2,
+            browser_timeout_seconds: 60,
+            archive_max_asset_size: 2 * 1024 * 1024,
+            archive_max_page_size: 15 * 1024 * 1024,
+            archive_inline_timeout_seconds: 120,
+            archive_warc: false,
+            import_download_interval_seconds: 5,
+            link_check_interval_days: 7,
+            renderer: RendererChoice::Auto,
+            renderer_fixtures: String::from("fixtures/renderer"),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(filename: &str) -> Result<Config, Error> {
+        if !std::path::Path::new(filename).exists() {
+            info!("No {} found, using default configuration", filename);
+            return Ok(Config::default());
+        }
+
+        let json_data = std::fs::read_to_string(filename)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+}
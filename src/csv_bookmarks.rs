@@ -0,0 +1,99 @@
+use failure::Error;
+
+use crate::importer::{self, ImportedBookmark};
+
+/// Which column of a csv file holds each part of a bookmark, by its name in the header row.
+/// Columns left empty aren't imported.
+#[derive(Debug, Clone)]
+pub struct CsvColumns {
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    pub tags: String,
+    pub created: String,
+    /// What separates the tags in the tags column, whitespace if left empty
+    pub tag_separator: String,
+}
+
+impl Default for CsvColumns {
+    fn default() -> CsvColumns {
+        CsvColumns {
+            url: "url".into(),
+            title: "title".into(),
+            description: "description".into(),
+            tags: "tags".into(),
+            created: "created".into(),
+            tag_separator: ",".into(),
+        }
+    }
+}
+
+impl CsvColumns {
+    /// Columns of Pocket's csv export
+    pub fn pocket() -> CsvColumns {
+        CsvColumns {
+            description: String::new(),
+            created: "time_added".into(),
+            tag_separator: "|".into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads the bookmarks in a csv file with a header row, one bookmark per row
+pub fn parse_bookmarks(data: &str, columns: &CsvColumns) -> Result<Vec<ImportedBookmark>, Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(data.trim_start_matches('\u{feff}').as_bytes());
+    let headers = reader.headers()?.clone();
+
+    let position = |name: &str| -> Option<usize> {
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+
+    let url_column = match position(&columns.url) {
+        None => bail!("No column named \"{}\" for urls", columns.url.trim()),
+        Some(x) => x,
+    };
+    let title_column = position(&columns.title);
+    let description_column = position(&columns.description);
+    let tags_column = position(&columns.tags);
+    let created_column = position(&columns.created);
+
+    let mut bookmarks = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|i| record.get(i))
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+
+        let tags = field(tags_column);
+        let tags = if columns.tag_separator.trim().is_empty() {
+            tags.split_whitespace().map(String::from).collect()
+        } else {
+            tags.split(columns.tag_separator.trim())
+                .map(String::from)
+                .collect()
+        };
+
+        bookmarks.push(ImportedBookmark {
+            title: field(title_column),
+            url: field(Some(url_column)),
+            description: field(description_column),
+            tags,
+            created: importer::parse_date(&field(created_column)),
+        });
+    }
+
+    Ok(bookmarks)
+}
@@ -0,0 +1,328 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::cache;
+
+/// Attempts made at downloading a pin before giving up on it
+const MAX_ATTEMPTS: u32 = 6;
+/// Delay before the first retry, doubled after every failed attempt
+const INITIAL_RETRY_DELAY_SECONDS: i64 = 60;
+const MAX_RETRY_DELAY_SECONDS: i64 = 6 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Ok,
+    Failed,
+}
+
+/// Download of the page and screenshot of one of a pin's urls. The latest job of every url is
+/// kept, even once it's done, so its outcome can be shown next to the pin.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DownloadJob {
+    pub username: String,
+    pub pin_id: String,
+    /// Which of the pin's urls is downloaded, each of them being cached separately
+    #[serde(default)]
+    pub url_index: usize,
+    pub url: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// When the job was queued, which tells it apart from a newer job for the same pin
+    pub queued: DateTime<Utc>,
+    pub next_attempt: DateTime<Utc>,
+}
+
+type JobKey = (String, String, usize);
+
+#[derive(Default)]
+struct QueueState {
+    jobs: HashMap<JobKey, DownloadJob>,
+    /// Keys of the pending jobs, so looking for the next job doesn't go through every finished one
+    pending: HashSet<JobKey>,
+    /// Jobs handed to a worker and not completed yet, along with the host they're downloading from
+    running: HashMap<JobKey, String>,
+}
+
+/// Download jobs for every user, saved under `{root}/{username}/{pin_id}.json` so pending jobs
+/// survive restarts. Jobs for a pin's other urls are saved as `{pin_id}_{url_index}.json`.
+#[derive(Clone)]
+pub struct DownloadQueue {
+    root: PathBuf,
+    max_per_host: usize,
+    state: Arc<(Mutex<QueueState>, Condvar)>,
+}
+
+/// Host a job downloads from, to limit how many jobs hit the same one at once
+fn job_host(job: &DownloadJob) -> String {
+    match job.url.parse::<actix_web::http::Uri>() {
+        Ok(uri) => uri.host().unwrap_or_default().to_lowercase(),
+        Err(_) => String::new(),
+    }
+}
+
+fn job_key(job: &DownloadJob) -> JobKey {
+    (job.username.clone(), job.pin_id.clone(), job.url_index)
+}
+
+/// Time to wait after a job failed for the `attempts`-th time
+fn retry_delay(attempts: u32) -> Duration {
+    let exponent = std::cmp::min(attempts.saturating_sub(1), 16);
+    Duration::seconds(std::cmp::min(
+        INITIAL_RETRY_DELAY_SECONDS << exponent,
+        MAX_RETRY_DELAY_SECONDS,
+    ))
+}
+
+impl DownloadQueue {
+    /// Opens the queue, picking up every job saved in `root`. At most `max_per_host` jobs for
+    /// the same host are handed out at once.
+    pub fn open<P: AsRef<Path>>(root: P, max_per_host: usize) -> Result<DownloadQueue, Error> {
+        let root = root.as_ref().to_path_buf();
+        let mut state = QueueState::default();
+
+        if root.exists() {
+            for user_directory in std::fs::read_dir(&root)? {
+                let user_directory = user_directory?.path();
+                if !user_directory.is_dir() {
+                    continue;
+                }
+
+                for file in std::fs::read_dir(&user_directory)? {
+                    let path = file?.path();
+                    if path.extension().map(|e| e != "json").unwrap_or(true) {
+                        continue;
+                    }
+
+                    match std::fs::read_to_string(&path)
+                        .map_err(Error::from)
+                        .and_then(|json_data| Ok(serde_json::from_str::<DownloadJob>(&json_data)?))
+                    {
+                        Err(err) => error!("Could not read download job {:?}: {}", path, err),
+                        Ok(job) => {
+                            if job.status == JobStatus::Pending {
+                                state.pending.insert(job_key(&job));
+                            }
+                            state.jobs.insert(job_key(&job), job);
+                        }
+                    }
+                }
+            }
+        }
+
+        let pending = state.pending.len();
+        if pending > 0 {
+            info!("Resuming {} pending downloads", pending);
+        }
+
+        Ok(DownloadQueue {
+            root,
+            max_per_host: std::cmp::max(max_per_host, 1),
+            state: Arc::new((Mutex::new(state), Condvar::new())),
+        })
+    }
+
+    fn job_filename(&self, username: &str, pin_id: &str, url_index: usize) -> PathBuf {
+        self.root
+            .join(username)
+            .join(format!("{}.json", cache::url_basename(pin_id, url_index)))
+    }
+
+    fn save_job(&self, job: &DownloadJob) -> Result<(), Error> {
+        let filename = self.job_filename(&job.username, &job.pin_id, job.url_index);
+        std::fs::create_dir_all(filename.parent().unwrap())?;
+        std::fs::write(filename, serde_json::to_string(job)?)?;
+        Ok(())
+    }
+
+    /// Queues a download for one of a pin's urls, replacing any previous job for it
+    pub fn enqueue(
+        &self,
+        username: &str,
+        pin_id: &str,
+        url_index: usize,
+        url: &str,
+    ) -> Result<(), Error> {
+        self.enqueue_at(username, pin_id, url_index, url, Utc::now())
+    }
+
+    /// Queues a download that won't start before `start`, like to spread out the downloads of
+    /// a big import
+    pub fn enqueue_at(
+        &self,
+        username: &str,
+        pin_id: &str,
+        url_index: usize,
+        url: &str,
+        start: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let now = Utc::now();
+        let job = DownloadJob {
+            username: username.to_string(),
+            pin_id: pin_id.to_string(),
+            url_index,
+            url: url.to_string(),
+            status: JobStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            queued: now,
+            next_attempt: std::cmp::max(start, now),
+        };
+
+        // Files are written with the lock held, so they end up in the same order as the changes
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        self.save_job(&job)?;
+        state.pending.insert(job_key(&job));
+        state.jobs.insert(job_key(&job), job);
+        condvar.notify_all();
+
+        Ok(())
+    }
+
+    /// Forgets the jobs of every url of a pin. Running ones will have their outcome ignored.
+    pub fn remove(&self, username: &str, pin_id: &str) -> Result<(), Error> {
+        self.remove_from(username, pin_id, 0)
+    }
+
+    /// Forgets the jobs of a pin's urls from the `first_index`-th on, like once the pin doesn't
+    /// have them anymore
+    pub fn remove_from(
+        &self,
+        username: &str,
+        pin_id: &str,
+        first_index: usize,
+    ) -> Result<(), Error> {
+        let (lock, _) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        let removed: Vec<JobKey> = state
+            .jobs
+            .keys()
+            .filter(|(u, p, i)| u == username && p == pin_id && *i >= first_index)
+            .cloned()
+            .collect();
+
+        for key in removed {
+            state.jobs.remove(&key);
+            state.pending.remove(&key);
+
+            let filename = self.job_filename(username, pin_id, key.2);
+            if filename.exists() {
+                std::fs::remove_file(filename)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_job(&self, username: &str, pin_id: &str, url_index: usize) -> Option<DownloadJob> {
+        let (lock, _) = &*self.state;
+        lock.lock()
+            .unwrap()
+            .jobs
+            .get(&(username.to_string(), pin_id.to_string(), url_index))
+            .cloned()
+    }
+
+    /// Waits until a pending job is due and hands it out. Call `complete` once it's done.
+    pub fn next_job(&self) -> DownloadJob {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        loop {
+            let now = Utc::now();
+
+            let mut running_per_host = HashMap::<&str, usize>::new();
+            for host in state.running.values() {
+                *running_per_host.entry(host).or_insert(0) += 1;
+            }
+
+            let next = state
+                .pending
+                .iter()
+                .filter(|key| !state.running.contains_key(*key))
+                .filter_map(|key| state.jobs.get(key).map(|job| (key, job, job_host(job))))
+                .filter(|(_, _, host)| {
+                    running_per_host.get(host.as_str()).cloned().unwrap_or(0) < self.max_per_host
+                })
+                .min_by_key(|(_, job, _)| job.next_attempt)
+                .map(|(key, job, host)| (key.clone(), job.clone(), host));
+
+            match next {
+                Some((key, job, host)) if job.next_attempt <= now => {
+                    state.running.insert(key, host);
+                    return job;
+                }
+                Some((_, job, _)) => {
+                    let wait = (job.next_attempt - now)
+                        .to_std()
+                        .unwrap_or_else(|_| std::time::Duration::from_secs(1));
+                    state = condvar.wait_timeout(state, wait).unwrap().0;
+                }
+                // Also reached when every due job's host is busy, in which case a job completing
+                // wakes us up
+                None => {
+                    state = condvar.wait(state).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Records the outcome of a job handed out by `next_job`, scheduling a retry if it failed
+    pub fn complete(&self, job: &DownloadJob, result: Result<(), Error>) -> Result<(), Error> {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        let key = job_key(job);
+        state.running.remove(&key);
+
+        // The pin might have been edited or deleted while it was being downloaded
+        let current = match state.jobs.get_mut(&key) {
+            Some(current) if current.queued == job.queued => current,
+            _ => {
+                condvar.notify_all();
+                return Ok(());
+            }
+        };
+
+        current.attempts += 1;
+        match result {
+            Ok(()) => {
+                current.status = JobStatus::Ok;
+                current.last_error = None;
+            }
+            Err(err) => {
+                current.last_error = Some(err.to_string());
+                if current.attempts >= MAX_ATTEMPTS {
+                    error!(
+                        "Giving up on downloading {} after {} attempts: {}",
+                        current.url, current.attempts, err
+                    );
+                    current.status = JobStatus::Failed;
+                } else {
+                    current.next_attempt = Utc::now() + retry_delay(current.attempts);
+                    info!(
+                        "Download of {} failed, retrying at {}: {}",
+                        current.url, current.next_attempt, err
+                    );
+                }
+            }
+        }
+
+        let current = current.clone();
+        if current.status != JobStatus::Pending {
+            state.pending.remove(&key);
+        }
+        condvar.notify_all();
+
+        self.save_job(&current)
+    }
+}
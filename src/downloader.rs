@@ -1,140 +1,145 @@
-use crate::errors::Context;
-use actix_web::http;
+use crate::cache;
+use crate::download_queue::{DownloadJob, DownloadQueue};
+use crate::fulltext::FullTextIndex;
+use crate::http_client::HttpClient;
+use crate::page_inliner::{self, InlineLimits};
+use crate::page_metadata::{self, PageMetadata};
+use crate::page_renderer::{PageRenderer, Renderers};
+use crate::pins::BackingStore;
+use crate::snapshots;
+use crate::thumbnail;
+use crate::url_resolver;
+use crate::warc::{self, RecordingClient};
 use failure::Error;
-use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct DownloadRequest {
-    pub url: String,
-    pub pin_id: String,
-    pub username: String,
+/// Everything download workers need, shared between all of them
+#[derive(Clone)]
+pub struct Downloader {
+    pub queue: DownloadQueue,
+    /// Pins get their empty fields filled in from their downloaded page
+    pub pins: BackingStore,
+    pub fulltext: FullTextIndex,
+    pub renderers: Renderers,
+    pub http_client: Arc<dyn HttpClient>,
+    pub inline_limits: Arc<InlineLimits>,
+    /// Time a browser gets for each page before it's killed
+    pub browser_timeout: Duration,
+    /// Whether to also save every capture as a WARC file
+    pub write_warc: bool,
 }
 
-fn take_screenshot(req: &DownloadRequest) -> Result<(), Error> {
-    // Dump screenshot
-    std::fs::create_dir_all(format!("cache/{}", req.username))?;
-
-    let window_width = 1280;
-    let aspect_ratio = 1.0 / 2.0;
-    let thumb_ratio = 5;
-    let scrollbar_width = 20;
-
-    let window_height = (window_width as f32 * aspect_ratio) as u32;
-
-    std::fs::remove_file("screenshot.png").unwrap_or(());
-    let output = std::process::Command::new("/usr/bin/firefox")
-        .arg("--headless")
-        .arg(format!("--window-size={},{}", window_width, window_height))
-        .arg("--screenshot")
-        .arg("screenshot.png")
-        .arg(&req.url)
-        .output()
-        .map_err(|e| {
-            error!("Could not execute browser to extract screenshot {}", e);
-            e
-        })?;
-
-    if !output.stdout.is_empty() {
-        info!(
-            "Output from browser: {}",
-            std::str::from_utf8(&output.stdout).unwrap_or("COULD NOT READ STDOUT")
-        );
-    }
+fn take_screenshot(
+    renderer: &dyn PageRenderer,
+    req: &DownloadJob,
+    timeout: Duration,
+) -> Result<(), Error> {
+    // Each screenshot gets its own scratch directory, so concurrent downloads can't mix them up
+    let scratch_directory = tempfile::Builder::new()
+        .prefix("recense_screenshot")
+        .tempdir()?;
+    let screenshot_filename = scratch_directory.path().join("screenshot.png");
 
-    if !output.stderr.is_empty() {
+    renderer.screenshot(
+        &req.url,
+        thumbnail::WINDOW_WIDTH,
+        thumbnail::WINDOW_HEIGHT,
+        &screenshot_filename,
+        timeout,
+    )?;
+
+    let thumbnail_filename = cache::cache_directory(&req.username).join(format!(
+        "{}.jpg",
+        cache::url_basename(&req.pin_id, req.url_index)
+    ));
+    thumbnail::save_thumbnail(&screenshot_filename, &thumbnail_filename).map_err(|e| {
         error!(
-            "Errors from browser: {}",
-            std::str::from_utf8(&output.stderr).unwrap_or("COULD NOT READ STDERR")
+            "Could not save screenshot file to filename {:?}. Error: {}",
+            thumbnail_filename, e
         );
-    }
-
-    // Move screenshot.png to the right place
-
-    {
-        let mut screenshot = image::open("screenshot.png")
-            .with_context(|| "Could not open generated screenshot.png file")?;
-        {
-            let cropped_screenshot = image::imageops::crop(
-                &mut screenshot,
-                0,
-                0,
-                window_width - scrollbar_width,
-                window_height,
-            );
-            let thumbnail = image::imageops::thumbnail(
-                &cropped_screenshot,
-                (window_width - scrollbar_width) / thumb_ratio,
-                window_height / thumb_ratio,
-            );
-            let screenshot_filename = format!("cache/{}/{}.jpg", &req.username, &req.pin_id);
-            thumbnail.save(&screenshot_filename).map_err(|e| {
-                error!(
-                    "Could not save screenshot file to filename {}. Error: {}",
-                    screenshot_filename, e
-                );
-                e
-            })?;
-        }
-    }
-
-    Ok(())
+        e
+    })
 }
 
-fn fix_html_references(handle: &mut html5ever::rcdom::Handle, server_url: &str) {
-    let node = handle;
-
-    if let html5ever::rcdom::NodeData::Element {
-        ref name,
-        ref attrs,
-        ..
-    } = node.data
-    {
-        if name.local.eq_str_ignore_ascii_case("a") || name.local.eq_str_ignore_ascii_case("link") {
-            for attr in attrs.borrow_mut().iter_mut() {
-                if attr.name.local.eq_str_ignore_ascii_case("href")
-                    && attr.value.to_string().starts_with('/')
-                {
-                    attr.value = html5ever::tendril::Tendril::format(format_args!(
-                        "{}{}",
-                        server_url, attr.value
-                    ));
-                }
-            }
-        }
+/// Saves the icon of a page along with its other cached files. Pages that don't declare one
+/// usually still have one at /favicon.ico.
+fn save_favicon(
+    req: &DownloadJob,
+    page_url: &str,
+    metadata: &PageMetadata,
+    http_client: &dyn HttpClient,
+    max_size: usize,
+) -> Result<(), Error> {
+    let icon_url = match &metadata.favicon_url {
+        Some(url) => url.clone(),
+        None => url::Url::parse(page_url)?.join("/favicon.ico")?.to_string(),
     };
 
-    for mut child in node.children.borrow_mut().iter_mut() {
-        fix_html_references(&mut child, server_url);
-    }
+    let response = http_client.get(&icon_url, max_size)?;
+    ensure!(
+        (200..300).contains(&response.status),
+        "Got status {} for {}",
+        response.status,
+        icon_url
+    );
+
+    thumbnail::save_favicon(
+        &response.body,
+        &cache::favicon_filename(&req.username, &req.pin_id),
+    )
 }
 
-fn download_link_source(browser_cmd: &str, req: &DownloadRequest) -> Result<(), Error> {
-    println!("Downloading link source");
-    let html_filename = format!("cache/{}/{}.html", &req.username, &req.pin_id);
+/// Caches the page of one of a pin's urls, returning what the page says about itself and whether
+/// it wasn't cached before. The pin's favicon and snapshots only come from its first url.
+fn download_link_source(
+    renderer: &dyn PageRenderer,
+    req: &DownloadJob,
+    downloader: &Downloader,
+) -> Result<(PageMetadata, bool), Error> {
+    info!("Downloading link source of {}", req.url);
+    let cache_directory = cache::cache_directory(&req.username);
+    std::fs::create_dir_all(&cache_directory)?;
+    let basename = cache::url_basename(&req.pin_id, req.url_index);
+    let html_filename = cache_directory.join(format!("{}.html", basename));
+    let is_first_url = req.url_index == 0;
+    let is_first_download = !html_filename.exists();
 
-    let url = req.url.parse::<http::uri::Uri>()?;
-    let url_authority = match url.authority_part() {
-        None => bail!("Can't download empty url {}", req.url),
-        Some(x) => x,
-    };
+    let page = renderer.dump_dom(&req.url, downloader.browser_timeout)?;
 
-    let scheme = match url.scheme_part() {
-        Some(x) => x.as_str(),
-        None => "http",
+    let recorder = if downloader.write_warc {
+        Some(RecordingClient::new(downloader.http_client.clone()))
+    } else {
+        None
+    };
+    let http_client: &dyn HttpClient = match &recorder {
+        Some(recorder) => recorder,
+        None => downloader.http_client.as_ref(),
     };
 
-    let server_url = format!("{}://{}", scheme, url_authority.as_str());
-
-    // Dump DOM contents
-    let output = std::process::Command::new(browser_cmd)
-        .arg("--headless")
-        .arg("--disable-gpu")
-        .arg("--dump-dom")
-        .arg(&req.url)
-        .output()
-        .map_err(|e| {
-            error!("Could not execute chromium to extract html {}", e);
-            e
-        })?;
+    // Relative urls depend on where redirects took the browser. Most browsers don't tell, so
+    // a HEAD request finds out instead. The WARC file needs the page itself, which tells as well.
+    let page_url = if recorder.is_some() {
+        http_client
+            .get(&req.url, downloader.inline_limits.max_total_size)
+            .map(|response| response.url)
+    } else if let Some(url) = page.url {
+        Ok(url)
+    } else {
+        http_client.head(&req.url).map(|response| response.url)
+    };
+    if let Err(err) = &page_url {
+        error!(
+            "Could not get {} to find where it redirects: {}",
+            req.url, err
+        );
+    }
+    // Without a record of the page itself, a WARC file would look complete but only hold assets
+    let is_page_recorded = recorder.is_some() && page_url.is_ok();
+    let page_url = match page_url {
+        Err(_) => req.url.clone(),
+        Ok(x) => x,
+    };
 
     use html5ever::tendril::TendrilSink;
 
@@ -146,13 +151,33 @@ fn download_link_source(browser_cmd: &str, req: &DownloadRequest) -> Result<(),
         ..Default::default()
     };
 
-    let mut dom = html5ever::parse_document(html5ever::rcdom::RcDom::default(), opts)
+    let dom = html5ever::parse_document(html5ever::rcdom::RcDom::default(), opts)
         .from_utf8()
-        .read_from(&mut &output.stdout[..])
+        .read_from(&mut &page.html[..])
         .unwrap();
 
-    // Iterate over all elements of the DOM, and change all hrefs, etc
-    fix_html_references(&mut dom.document, &server_url);
+    url_resolver::resolve_document_urls(&dom.document, &page_url)?;
+
+    // Icons get inlined along with everything else, so they have to be found before that
+    let metadata = page_metadata::extract_metadata(&dom.document);
+    if is_first_url {
+        if let Err(err) = save_favicon(
+            req,
+            &page_url,
+            &metadata,
+            http_client,
+            downloader.inline_limits.max_asset_size,
+        ) {
+            info!("No icon saved for {}: {}", req.url, err);
+        }
+    }
+
+    page_inliner::inline_assets(
+        &dom.document,
+        &page_url,
+        http_client,
+        &downloader.inline_limits,
+    )?;
 
     let mut out = Vec::<u8>::new();
     html5ever::serialize(&mut out, &dom.document, Default::default())?;
@@ -162,41 +187,89 @@ fn download_link_source(browser_cmd: &str, req: &DownloadRequest) -> Result<(),
         e
     })?;
 
-    Ok(())
+    if is_first_url {
+        if let Err(err) = snapshots::record_snapshot(&req.username, &req.pin_id, &out) {
+            error!("Could not save snapshot of {}: {}", req.url, err);
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        if is_page_recorded {
+            warc::write_warc(
+                &cache_directory.join(format!("{}.warc", basename)),
+                &recorder.into_captures(),
+            )?;
+        } else {
+            warn!(
+                "Not saving a WARC file for {}, it would be incomplete without the page",
+                req.url
+            );
+        }
+    }
+
+    Ok((metadata, is_first_download))
 }
 
-pub fn downloader_thread(channel: mpsc::Receiver<DownloadRequest>) {
-    let active_browser = [
-        "/usr/bin/google-chrome",
-        "/usr/bin/chromium",
-        "/usr/bin/chromium-browser",
-    ]
-    .iter()
-    .find(|browser| std::path::Path::new(&browser).exists())
-    .ok_or_else(|| {
-        panic!("Could not find any installed chromium-based browser to take screenshots of sites")
-    })
-    .unwrap();
+impl Downloader {
+    /// Downloads the page and screenshot of one of a pin's urls, failing if either of them
+    /// couldn't be saved. Steps without a renderer are skipped.
+    fn run_job(&self, job: &DownloadJob) -> Result<(), Error> {
+        let mut errors = vec![];
 
-    loop {
-        let download_request = channel.recv().unwrap();
+        if let Some(renderer) = &self.renderers.screenshots {
+            if let Err(err) = take_screenshot(renderer.as_ref(), job, self.browser_timeout) {
+                error!(
+                    "Error trying to generate screenshot: {}\n{}",
+                    err,
+                    err.backtrace()
+                );
+                errors.push(format!("Screenshot: {}", err));
+            }
+        }
 
-        println!("Getting url: {}", download_request.url);
+        if let Some(renderer) = &self.renderers.pages {
+            match download_link_source(renderer.as_ref(), job, self) {
+                Err(err) => {
+                    error!(
+                        "Error trying to download source: {}\n{}",
+                        err,
+                        err.backtrace()
+                    );
+                    errors.push(format!("Page: {}", err));
+                }
+                Ok((metadata, is_first_download)) => {
+                    if let Err(err) = self.fulltext.index_cached_page(&job.username, &job.pin_id) {
+                        error!("Error indexing downloaded page: {}", err)
+                    }
+                    if let Err(err) =
+                        self.pins
+                            .fill_in_page_metadata(job, &metadata, is_first_download)
+                    {
+                        error!("Error filling in pin from its page: {}", err)
+                    }
+                }
+            }
+        }
 
-        if let Err(err) = take_screenshot(&download_request) {
-            error!(
-                "Error trying to generate screenshot: {}\n{}",
-                err,
-                err.backtrace()
-            )
+        if !errors.is_empty() {
+            bail!("{}", errors.join(", "));
         }
 
-        if let Err(err) = download_link_source(&active_browser, &download_request) {
-            error!(
-                "Error trying to download source: {}\n{}",
-                err,
-                err.backtrace()
-            )
+        Ok(())
+    }
+
+    /// Worker taking jobs off the download queue. Several of them can run at once.
+    pub fn worker_thread(&self) {
+        loop {
+            let job = self.queue.next_job();
+
+            info!("Getting url: {} (attempt {})", job.url, job.attempts + 1);
+
+            let result = self.run_job(&job);
+
+            if let Err(err) = self.queue.complete(&job, result) {
+                error!("Error saving download job for {}: {}", job.url, err);
+            }
         }
     }
 }
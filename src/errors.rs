@@ -5,6 +5,28 @@ pub trait Context<T, E> {
         F: FnOnce() -> C;
 }
 
+#[derive(Debug)]
+struct InnerFail {
+    inner: failure::Error,
+    context: String,
+}
+
+impl std::fmt::Display for InnerFail {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Error: \"{}\". With context: \"{}\"",
+            self.inner, self.context
+        )
+    }
+}
+
+impl failure::Fail for InnerFail {
+    fn cause(&self) -> Option<&dyn failure::Fail> {
+        Some(self.inner.as_fail())
+    }
+}
+
 impl<T, E> Context<T, E> for ::std::result::Result<T, E>
 where
     E: failure::Fail,
@@ -16,20 +38,10 @@ where
     {
         match self {
             Ok(x) => Ok(x),
-            Err(err) => {
-                #[derive(Fail, Debug)]
-                #[fail(display = "Error: \"{}\". With context: \"{}\"", inner, context)]
-                struct InnerFail {
-                    #[cause]
-                    inner: failure::Error,
-                    context: String,
-                }
-
-                Err(failure::Error::from(InnerFail {
-                    inner: failure::Error::from(err),
-                    context: context_func().to_string(),
-                }))
-            }
+            Err(err) => Err(failure::Error::from(InnerFail {
+                inner: failure::Error::from(err),
+                context: context_func().to_string(),
+            })),
         }
     }
 }
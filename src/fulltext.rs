@@ -0,0 +1,417 @@
+use failure::Error;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::cache;
+use crate::link_checker::{LinkChecker, LinkStatus};
+use crate::pins::Pin;
+use crate::search_query::SearchContext;
+
+/// Characters shown on each side of a match in search snippets
+const SNIPPET_CONTEXT: usize = 80;
+
+/// Extracts the text a person would see from an html page, skipping scripts, styles and such
+pub fn extract_text(html: &[u8]) -> String {
+    use html5ever::rcdom::{Handle, NodeData, RcDom};
+    use html5ever::tendril::TendrilSink;
+
+    fn walk(node: &Handle, text: &mut String) {
+        match node.data {
+            NodeData::Text { ref contents } => {
+                text.push_str(&contents.borrow());
+                text.push(' ');
+            }
+            NodeData::Element { ref name, .. } => {
+                let is_invisible = ["script", "style", "noscript", "template", "svg"]
+                    .iter()
+                    .any(|tag| name.local.eq_str_ignore_ascii_case(tag));
+                if is_invisible {
+                    return;
+                }
+            }
+            _ => {}
+        }
+
+        for child in node.children.borrow().iter() {
+            walk(child, text);
+        }
+    }
+
+    let dom = html5ever::parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .one(html);
+
+    let mut text = String::new();
+    walk(&dom.document, &mut text);
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Splits text into lowercase words, which is what gets indexed and searched for
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && w.chars().count() <= 64)
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Inverted index over the cached pages of a single user
+#[derive(Default)]
+pub struct UserTextIndex {
+    /// Word -> pin id -> number of times the word appears in the pin's page
+    postings: BTreeMap<String, HashMap<String, u32>>,
+    /// Pin id -> text of its pages, for search snippets and to remove pages from `postings`
+    texts: HashMap<String, String>,
+}
+
+impl UserTextIndex {
+    fn add_page(&mut self, id: &str, text: &str) {
+        self.remove_page(id);
+
+        let mut counts = HashMap::<String, u32>::new();
+        for word in tokenize(text) {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+
+        for (word, count) in counts {
+            self.postings
+                .entry(word)
+                .or_default()
+                .insert(id.to_string(), count);
+        }
+        self.texts.insert(id.to_string(), text.to_string());
+    }
+
+    fn remove_page(&mut self, id: &str) {
+        if let Some(text) = self.texts.remove(id) {
+            let words: HashSet<String> = tokenize(&text).into_iter().collect();
+            for word in words {
+                if let Some(pins) = self.postings.get_mut(&word) {
+                    pins.remove(id);
+                    if pins.is_empty() {
+                        self.postings.remove(&word);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Occurrences per pin of every indexed word starting with `prefix`
+    fn prefix_occurrences(&self, prefix: &str) -> HashMap<&str, u32> {
+        let mut result = HashMap::new();
+
+        for (_, pins) in self
+            .postings
+            .range(prefix.to_string()..)
+            .take_while(|(word, _)| word.starts_with(prefix))
+        {
+            for (id, count) in pins {
+                *result.entry(id.as_str()).or_insert(0) += count;
+            }
+        }
+
+        result
+    }
+
+    /// Ids of the pins whose page contains every word of `text`, or words starting with them
+    pub fn pins_containing(&self, text: &str) -> HashSet<String> {
+        let words = tokenize(text);
+
+        let mut result: Option<HashSet<String>> = None;
+        for word in &words {
+            let pins: HashSet<String> = self
+                .prefix_occurrences(word)
+                .keys()
+                .map(|id| id.to_string())
+                .collect();
+
+            result = Some(match result {
+                None => pins,
+                Some(previous) => previous.intersection(&pins).cloned().collect(),
+            });
+        }
+
+        result.unwrap_or_default()
+    }
+
+    /// Relevance of a pin for a list of search terms. Matches in the pin's own fields count for
+    /// more than matches in its page, which are weighted by how rare each word is (tf-idf).
+    pub fn score(&self, pin: &Pin, terms: &[String]) -> f32 {
+        let page_count = std::cmp::max(self.texts.len(), 1) as f32;
+
+        let mut score = 0.0;
+        for term in terms {
+            if pin.title.to_lowercase().contains(term.as_str()) {
+                score += 5.0;
+            }
+            if pin
+                .tags
+                .iter()
+                .any(|t| t.to_lowercase().contains(term.as_str()))
+            {
+                score += 3.0;
+            }
+            if pin.description.to_lowercase().contains(term.as_str()) {
+                score += 2.0;
+            }
+            if pin
+                .urls
+                .iter()
+                .any(|u| u.to_lowercase().contains(term.as_str()))
+            {
+                score += 1.0;
+            }
+
+            for word in tokenize(term) {
+                let occurrences = self.prefix_occurrences(&word);
+                if let Some(count) = occurrences.get(pin.id.as_str()) {
+                    let idf = (page_count / occurrences.len() as f32).ln() + 1.0;
+                    score += (1.0 + (*count as f32).ln()) * idf;
+                }
+            }
+        }
+
+        score
+    }
+
+    /// Returns a fragment of the pages of a pin around the first match of any of `terms`
+    pub fn snippet(&self, id: &str, terms: &[String]) -> Option<String> {
+        page_snippet(self.texts.get(id)?, terms)
+    }
+}
+
+/// Full text indexes for every user, built from their cached pages in the background once their
+/// pins are loaded, or the first time they search
+#[derive(Clone, Default)]
+pub struct FullTextIndex {
+    users: Arc<RwLock<HashMap<String, UserTextIndex>>>,
+    /// Held while a user's index is being built, and by every change to it, so a page cached
+    /// while the index is being built isn't lost when the build finishes
+    user_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl FullTextIndex {
+    pub fn new() -> FullTextIndex {
+        FullTextIndex::default()
+    }
+
+    fn user_lock(&self, username: &str) -> Arc<Mutex<()>> {
+        self.user_locks
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn load_user(username: &str) -> Result<UserTextIndex, Error> {
+        let mut user_index = UserTextIndex::default();
+
+        let directory = cache::cache_directory(username);
+        if !directory.exists() {
+            return Ok(user_index);
+        }
+
+        // Pins with several urls have a page for each, which are indexed as one
+        let mut pages = HashMap::<String, String>::new();
+        for file in std::fs::read_dir(directory)? {
+            let path = match file {
+                Err(_) => continue,
+                Ok(x) => x.path(),
+            };
+
+            let id = match path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .and_then(cache::parse_cached_filename)
+            {
+                Some((id, _, "html")) => id.to_string(),
+                _ => continue,
+            };
+
+            match std::fs::read(&path) {
+                Err(err) => error!("Could not read cached page {:?}: {}", path, err),
+                Ok(html) => {
+                    let text = pages.entry(id).or_default();
+                    text.push_str(&extract_text(&html));
+                    text.push('\n');
+                }
+            }
+        }
+
+        for (id, text) in pages {
+            user_index.add_page(&id, &text);
+        }
+
+        Ok(user_index)
+    }
+
+    /// Builds the index of a user unless it's already there
+    fn ensure_loaded(&self, username: &str) -> Result<(), Error> {
+        if self.users.read().unwrap().contains_key(username) {
+            return Ok(());
+        }
+
+        let user_lock = self.user_lock(username);
+        let _guard = user_lock.lock().unwrap();
+
+        // Someone else may have built the index while we waited for the lock
+        if self.users.read().unwrap().contains_key(username) {
+            return Ok(());
+        }
+
+        let user_index = FullTextIndex::load_user(username)?;
+        self.users
+            .write()
+            .unwrap()
+            .insert(username.to_string(), user_index);
+        Ok(())
+    }
+
+    /// Starts building the index of a user in another thread, so it's usually ready by the time
+    /// they search
+    pub fn load_in_background(&self, username: &str) {
+        if self.users.read().unwrap().contains_key(username) {
+            return;
+        }
+
+        let index = self.clone();
+        let username = username.to_string();
+        std::thread::spawn(move || {
+            if let Err(err) = index.ensure_loaded(&username) {
+                error!(
+                    "Could not build full text index for {}: {:?}",
+                    username, err
+                );
+            }
+        });
+    }
+
+    /// Runs `f` with the index of a user, building it first or waiting for it to be built if
+    /// needed
+    pub fn with_user<F, T>(&self, username: &str, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&UserTextIndex) -> T,
+    {
+        self.ensure_loaded(username)?;
+        Ok(f(&self.users.read().unwrap()[username]))
+    }
+
+    /// Adds or replaces the pages of every url of a pin, or forgets the pin if it has none. Users
+    /// whose index isn't built yet are skipped, as the pages will be read from the cache when
+    /// it's built.
+    pub fn index_cached_page(&self, username: &str, id: &str) -> Result<(), Error> {
+        let pages = cache::cached_pages(username, id)?;
+        if pages.is_empty() {
+            self.remove_page(username, id);
+            return Ok(());
+        }
+
+        let mut text = String::new();
+        for page in pages {
+            text.push_str(&extract_text(&std::fs::read(page)?));
+            text.push('\n');
+        }
+
+        let user_lock = self.user_lock(username);
+        let _guard = user_lock.lock().unwrap();
+
+        if let Some(user_index) = self.users.write().unwrap().get_mut(username) {
+            user_index.add_page(id, &text);
+        }
+        Ok(())
+    }
+
+    pub fn remove_page(&self, username: &str, id: &str) {
+        let user_lock = self.user_lock(username);
+        let _guard = user_lock.lock().unwrap();
+
+        if let Some(user_index) = self.users.write().unwrap().get_mut(username) {
+            user_index.remove_page(id);
+        }
+    }
+}
+
+fn page_snippet(text: &str, terms: &[String]) -> Option<String> {
+    let words: Vec<String> = terms.iter().flat_map(|t| tokenize(t)).collect();
+
+    let mut match_start = None;
+    let mut word_start = None;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if c.is_alphanumeric() {
+            word_start = word_start.or(Some(i));
+        } else if let Some(start) = word_start.take() {
+            let word = text[start..i].to_lowercase();
+            if words.iter().any(|w| word.starts_with(w.as_str())) {
+                match_start = Some(start);
+                break;
+            }
+        }
+    }
+    let match_start = match_start?;
+
+    let start = text[..match_start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT)
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    let end = text[match_start..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT * 2)
+        .map(|(i, _)| match_start + i)
+        .unwrap_or_else(|| text.len());
+
+    Some(format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        &text[start..end],
+        if end < text.len() { "…" } else { "" }
+    ))
+}
+
+/// Lets search queries look into a user's cached pages. Which pages contain each text term is
+/// looked up once up front, instead of once per pin.
+pub struct PageSearchContext<'a> {
+    username: &'a str,
+    pages_containing: HashMap<String, HashSet<String>>,
+    links: &'a LinkChecker,
+}
+
+impl<'a> PageSearchContext<'a> {
+    pub fn new(
+        username: &'a str,
+        index: &UserTextIndex,
+        terms: &[String],
+        links: &'a LinkChecker,
+    ) -> Self {
+        PageSearchContext {
+            username,
+            links,
+            pages_containing: terms
+                .iter()
+                .map(|term| (term.clone(), index.pins_containing(term)))
+                .collect(),
+        }
+    }
+}
+
+impl<'a> SearchContext for PageSearchContext<'a> {
+    fn has_cache(&self, pin: &Pin) -> bool {
+        cache::has_cached_page(self.username, &pin.id)
+    }
+
+    fn link_status(&self, pin: &Pin) -> Option<LinkStatus> {
+        self.links.get_status(self.username, &pin.id)
+    }
+
+    fn page_contains(&self, pin: &Pin, text: &str) -> bool {
+        self.pages_containing
+            .get(text)
+            .map(|pins| pins.contains(&pin.id))
+            .unwrap_or(false)
+    }
+}
@@ -9,7 +9,7 @@ pub struct HTMLRenderer {
 }
 
 fn format_datetime(v: &str) -> String {
-    let dt = match DateTime::<FixedOffset>::parse_from_rfc3339(&v) {
+    let dt = match DateTime::<FixedOffset>::parse_from_rfc3339(v) {
         Err(x) => {
             error!("{}", x);
             return String::from("unknown");
@@ -50,9 +50,30 @@ fn format_datetime(v: &str) -> String {
 }
 
 handlebars_helper!(format_time: |s: str| format_datetime(s) );
-handlebars_helper!(is_empty_string: |s: str| s == "" );
+handlebars_helper!(is_empty_string: |s: str| s.is_empty() );
 handlebars_helper!(allow_wrapping: |s: str| String::from(s).replace("_", "<wbr>_"));
 
+/// Replaces the built-in `eq`, which only compares integers, with one comparing any two values
+struct Equals;
+
+impl handlebars::HelperDef for Equals {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        _: &'reg handlebars::Handlebars,
+        _: &'rc handlebars::Context,
+        _: &mut handlebars::RenderContext<'reg>,
+    ) -> Result<Option<handlebars::ScopedJson<'reg, 'rc>>, handlebars::RenderError> {
+        let (a, b) = match (h.param(0), h.param(1)) {
+            (Some(a), Some(b)) => (a.value(), b.value()),
+            _ => return Err(handlebars::RenderError::new("`eq` needs two parameters")),
+        };
+        Ok(Some(handlebars::ScopedJson::Derived(
+            handlebars::JsonValue::from(a == b),
+        )))
+    }
+}
+
 impl HTMLRenderer {
     fn initialize_handlebars() -> handlebars::Handlebars {
         let mut hbars = handlebars::Handlebars::new();
@@ -60,6 +81,7 @@ impl HTMLRenderer {
         hbars.register_helper("format_time", Box::new(format_time));
         hbars.register_helper("is_empty_string", Box::new(is_empty_string));
         hbars.register_helper("allow_wrapping", Box::new(allow_wrapping));
+        hbars.register_helper("eq", Box::new(Equals));
 
         if let Err(err) = hbars.register_templates_directory(".html", "templates") {
             error!("Error loading HTML templates: {}", err);
@@ -97,10 +119,25 @@ impl HTMLRenderer {
     }
 }
 
+/// Escapes text to be written into html, either between tags or in a quoted attribute
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
 pub fn render_markdown_string(markdown_input: &str) -> Result<String, Error> {
     use pulldown_cmark::{html, Options, Parser};
 
-    let parser = Parser::new_ext(&markdown_input, Options::all());
+    let parser = Parser::new_ext(markdown_input, Options::all());
 
     // Write to String buffer.
     let mut html_output = String::new();
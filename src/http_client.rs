@@ -0,0 +1,123 @@
+use failure::Error;
+use std::io::Read;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct HttpResponse {
+    /// Url the response came from, after following redirects
+    pub url: String,
+    pub http_version: String,
+    pub status: u16,
+    pub status_text: String,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Body with any content encoding, like gzip, already undone
+    pub body: Vec<u8>,
+}
+
+/// Makes plain http requests, for everything that doesn't need a whole browser
+pub trait HttpClient: Send + Sync {
+    /// GETs `url`, following redirects. Error statuses are returned as responses, but bodies
+    /// bigger than `max_size` bytes are an error.
+    fn get(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error>;
+
+    /// Like `get`, but redirects are returned as they are instead of being followed
+    fn get_single(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error>;
+
+    /// Like `get`, but with a HEAD request, so the response has no body
+    fn head(&self, url: &str) -> Result<HttpResponse, Error>;
+}
+
+pub struct UreqClient {
+    agent: ureq::Agent,
+    /// Same as `agent`, but without following redirects
+    single_agent: ureq::Agent,
+}
+
+impl UreqClient {
+    pub fn new(timeout: Duration) -> UreqClient {
+        let builder = || {
+            ureq::AgentBuilder::new()
+                .timeout(timeout)
+                .user_agent(concat!("recense/", env!("CARGO_PKG_VERSION")))
+        };
+
+        UreqClient {
+            agent: builder().build(),
+            single_agent: builder().redirects(0).build(),
+        }
+    }
+}
+
+impl UreqClient {
+    fn call(
+        agent: &ureq::Agent,
+        method: &str,
+        url: &str,
+        max_size: usize,
+    ) -> Result<HttpResponse, Error> {
+        let response = match agent.request(method, url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => bail!("Could not get {}: {}", url, err),
+        };
+
+        let final_url = response.get_url().to_string();
+        let http_version = response.http_version().to_string();
+        let status = response.status();
+        let status_text = response.status_text().to_string();
+        let content_type = response.header("Content-Type").map(String::from);
+        // Headers sent several times, like Set-Cookie, show up once per value
+        let mut header_names: Vec<String> = vec![];
+        for name in response.headers_names() {
+            if !header_names.contains(&name) {
+                header_names.push(name);
+            }
+        }
+        let headers = header_names
+            .iter()
+            .flat_map(|name| {
+                response
+                    .all(name)
+                    .into_iter()
+                    .map(move |value| (name.clone(), value.to_string()))
+            })
+            .collect();
+
+        let mut body = vec![];
+        response
+            .into_reader()
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut body)?;
+        ensure!(
+            body.len() <= max_size,
+            "{} is bigger than {} bytes",
+            url,
+            max_size
+        );
+
+        Ok(HttpResponse {
+            url: final_url,
+            http_version,
+            status,
+            status_text,
+            content_type,
+            headers,
+            body,
+        })
+    }
+}
+
+impl HttpClient for UreqClient {
+    fn get(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error> {
+        UreqClient::call(&self.agent, "GET", url, max_size)
+    }
+
+    fn get_single(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error> {
+        UreqClient::call(&self.single_agent, "GET", url, max_size)
+    }
+
+    fn head(&self, url: &str) -> Result<HttpResponse, Error> {
+        UreqClient::call(&self.agent, "HEAD", url, 0)
+    }
+}
@@ -0,0 +1,166 @@
+use chrono::prelude::*;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::csv_bookmarks::{self, CsvColumns};
+use crate::netscape_bookmarks;
+use crate::pinboard;
+use crate::pins::{self, Pin};
+use crate::url_normalizer;
+use crate::user::UserInfo;
+
+/// A bookmark read from another program's export, before it becomes a pin
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ImportedBookmark {
+    pub title: String,
+    pub url: String,
+    pub description: String,
+    /// Tags as the other program had them, which may not be valid tags here yet
+    pub tags: Vec<String>,
+    pub created: Option<DateTime<Utc>>,
+}
+
+/// An entry that couldn't be imported, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportFailure {
+    pub entry: String,
+    pub error: String,
+}
+
+/// Outcome of an import, shown once it's done
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    pub created: usize,
+    /// Urls left out because they were already saved, or came up earlier in the same import
+    pub skipped_duplicates: Vec<String>,
+    pub failed: Vec<ImportFailure>,
+    /// Set when nothing was saved yet, and `created` only counts the pins that would be
+    pub dry_run: bool,
+    /// Pins an import would create, only kept for a dry run
+    pub preview: Vec<Pin>,
+}
+
+/// Turns a folder or tag name from another program into a tag, with underscores for spaces
+fn tag_from_name(name: &str) -> Option<String> {
+    pins::parse_tags(&name.split_whitespace().collect::<Vec<_>>().join("_")).pop()
+}
+
+/// Reads a date the way exports write them: seconds since 1970, though some write milli or
+/// microseconds, or a date with an optional time, as UTC unless it says otherwise
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+
+    if let Ok(number) = value.parse::<i64>() {
+        let seconds = if number > 100_000_000_000_000 {
+            number / 1_000_000
+        } else if number > 100_000_000_000 {
+            number / 1_000
+        } else {
+            number
+        };
+        return Utc.timestamp_opt(seconds, 0).single();
+    }
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|date| Utc.from_utc_datetime(&date))
+}
+
+/// Reads the bookmarks in a file exported by another program: `netscape` for a browser's
+/// `bookmarks.html`, `pinboard`, `pocket`, or `csv` with the given columns
+pub fn parse_export(
+    format: &str,
+    data: &str,
+    columns: &CsvColumns,
+) -> Result<Vec<ImportedBookmark>, Error> {
+    let bookmarks = match format {
+        "netscape" => netscape_bookmarks::parse_bookmarks(data),
+        "pinboard" => pinboard::parse_bookmarks(data)?,
+        // Pocket used to export an html page, before switching to csv
+        "pocket" if data.trim_start().starts_with('<') => netscape_bookmarks::parse_bookmarks(data),
+        "pocket" => csv_bookmarks::parse_bookmarks(data, &CsvColumns::pocket())?,
+        "csv" => csv_bookmarks::parse_bookmarks(data, columns)?,
+        _ => bail!("Unknown format {}", format),
+    };
+
+    ensure!(!bookmarks.is_empty(), "No bookmarks found in the file");
+    Ok(bookmarks)
+}
+
+fn pending_import_filename(username: &str) -> PathBuf {
+    UserInfo::data_file(username, "pending_import.json")
+}
+
+/// Keeps the bookmarks of a dry run until the user confirms importing them, replacing any
+/// earlier dry run
+pub fn save_pending_import(username: &str, bookmarks: &[ImportedBookmark]) -> Result<(), Error> {
+    std::fs::write(
+        pending_import_filename(username),
+        serde_json::to_string(bookmarks)?,
+    )?;
+    Ok(())
+}
+
+/// Returns the bookmarks of the latest dry run, which are forgotten so they're only imported once
+pub fn take_pending_import(username: &str) -> Result<Vec<ImportedBookmark>, Error> {
+    let filename = pending_import_filename(username);
+    ensure!(filename.exists(), "No import is waiting to be confirmed");
+
+    let bookmarks = serde_json::from_str(&std::fs::read_to_string(&filename)?)?;
+    std::fs::remove_file(filename)?;
+    Ok(bookmarks)
+}
+
+impl ImportedBookmark {
+    /// How the bookmark is called in an import summary
+    pub fn name(&self) -> String {
+        if self.title.trim().is_empty() {
+            self.url.clone()
+        } else {
+            format!("{} ({})", self.title, self.url)
+        }
+    }
+
+    /// Makes a pin out of the bookmark, failing if its url isn't one a page can be downloaded
+    /// from, like a bookmarklet
+    pub fn to_pin(&self) -> Result<Pin, Error> {
+        let url = self.url.trim();
+        ensure!(!url.is_empty(), "No url");
+        if let Ok(parsed) = url::Url::parse(url) {
+            ensure!(
+                parsed.scheme() == "http" || parsed.scheme() == "https",
+                "Unsupported url {}",
+                url
+            );
+        }
+        let url = url_normalizer::normalize_url(url);
+        ensure!(
+            url::Url::parse(&url).is_ok_and(|u| u.host().is_some()),
+            "Invalid url {}",
+            url
+        );
+
+        let mut pin = Pin::new();
+        pin.title = self.title.trim().to_string();
+        pin.urls = vec![url];
+        pin.description = self.description.trim().to_string();
+        pin.tags = self.tags.iter().filter_map(|t| tag_from_name(t)).collect();
+        pin.tags.sort_unstable();
+        pin.tags.dedup();
+        if let Some(created) = self.created {
+            pin.created = created;
+        }
+
+        Ok(pin)
+    }
+}
@@ -0,0 +1,355 @@
+use chrono::prelude::*;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::pins::{Pin, PinStore, TrashedPin};
+
+/// Stores every revision of a pin as its own json file, under `{root}/{username}/{id}_v{n}.json`,
+/// with the number of the latest one in `{id}.latest` next to them. Trashed pins get their files
+/// moved to `{trash_root}/{username}/`, next to a `{id}.trash` file recording when they were
+/// deleted.
+pub struct JsonDirStore {
+    root: PathBuf,
+    trash_root: PathBuf,
+}
+
+#[derive(Deserialize, Serialize)]
+struct TrashInfo {
+    deleted: DateTime<Utc>,
+}
+
+impl JsonDirStore {
+    pub fn new<P: AsRef<Path>>(root: P, trash_root: P) -> JsonDirStore {
+        JsonDirStore {
+            root: root.as_ref().to_path_buf(),
+            trash_root: trash_root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn pin_filename(directory: &Path, id: &str, revision: u32) -> PathBuf {
+        directory.join(format!("{}_v{}.json", id, revision))
+    }
+
+    fn latest_filename(directory: &Path, id: &str) -> PathBuf {
+        directory.join(format!("{}.latest", id))
+    }
+
+    fn trash_info_filename(directory: &Path, id: &str) -> PathBuf {
+        directory.join(format!("{}.trash", id))
+    }
+
+    fn pin_directory(&self, username: &str) -> PathBuf {
+        self.root.join(username)
+    }
+
+    fn trash_directory(&self, username: &str) -> PathBuf {
+        self.trash_root.join(username)
+    }
+
+    /// Splits a pin filename into its pin id and revision number
+    fn parse_pin_filename(path: &Path) -> Option<(String, u32)> {
+        if path.extension()? != "json" {
+            return None;
+        }
+
+        let stem = path.file_stem()?.to_str()?;
+        let separator = stem.rfind("_v")?;
+        let revision = stem[separator + 2..].parse().ok()?;
+
+        Some((stem[..separator].to_string(), revision))
+    }
+
+    /// Returns the id and latest revision number of every pin in a directory
+    fn latest_revisions(directory: &Path) -> Result<HashMap<String, u32>, Error> {
+        let mut result = HashMap::new();
+
+        if !directory.exists() {
+            return Ok(result);
+        }
+
+        for file in std::fs::read_dir(directory)? {
+            let path = match file {
+                Err(_) => continue,
+                Ok(x) => x.path(),
+            };
+
+            if let Some((id, revision)) = JsonDirStore::parse_pin_filename(&path) {
+                let latest = result.entry(id).or_insert(revision);
+                *latest = std::cmp::max(*latest, revision);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Latest revision of a single pin, as recorded in its `.latest` file. Pins saved before
+    /// there were such files, or whose recorded revision was removed by hand, get their directory
+    /// listed instead.
+    fn find_latest_revision(directory: &Path, id: &str) -> Option<u32> {
+        let recorded = std::fs::read_to_string(JsonDirStore::latest_filename(directory, id))
+            .ok()
+            .and_then(|revision| revision.trim().parse().ok());
+
+        match recorded {
+            Some(revision) if JsonDirStore::pin_filename(directory, id, revision).exists() => {
+                Some(revision)
+            }
+            // A new pin has neither file, so there's no need to look any further
+            None if !JsonDirStore::pin_filename(directory, id, 0).exists() => None,
+            _ => std::fs::read_dir(directory)
+                .ok()?
+                .filter_map(|file| JsonDirStore::parse_pin_filename(&file.ok()?.path()))
+                .filter(|(file_id, _)| file_id == id)
+                .map(|(_, revision)| revision)
+                .max(),
+        }
+    }
+
+    fn latest_revision(directory: &Path, id: &str) -> Result<u32, Error> {
+        match JsonDirStore::find_latest_revision(directory, id) {
+            None => bail!("Pin id {} does not exist", id),
+            Some(x) => Ok(x),
+        }
+    }
+
+    /// Moves all revisions of a pin from one directory to another
+    fn move_pin(from: &Path, to: &Path, id: &str) -> Result<(), Error> {
+        let latest = JsonDirStore::latest_revision(from, id)?;
+
+        std::fs::create_dir_all(to)?;
+
+        for revision in 0..=latest {
+            let filename = JsonDirStore::pin_filename(from, id, revision);
+            if filename.exists() {
+                std::fs::rename(filename, JsonDirStore::pin_filename(to, id, revision))?;
+            }
+        }
+        std::fs::write(JsonDirStore::latest_filename(to, id), latest.to_string())?;
+
+        let latest_filename = JsonDirStore::latest_filename(from, id);
+        if latest_filename.exists() {
+            std::fs::remove_file(latest_filename)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_pin_from_filename(&self, filename: &Path) -> Result<Pin, Error> {
+        let json_data = std::fs::read_to_string(filename)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+}
+
+impl PinStore for JsonDirStore {
+    fn get_pin(&self, username: &str, id: &str) -> Result<Pin, Error> {
+        let directory = self.pin_directory(username);
+        let revision = JsonDirStore::latest_revision(&directory, id)?;
+
+        self.get_pin_from_filename(&JsonDirStore::pin_filename(&directory, id, revision))
+    }
+
+    fn add_pin(&self, username: &str, pin: &Pin) -> Result<(), Error> {
+        let pin_json = serde_json::to_string(pin).unwrap();
+
+        let directory = self.pin_directory(username);
+        let revision = match JsonDirStore::find_latest_revision(&directory, &pin.id) {
+            None => 0,
+            Some(latest) => latest + 1,
+        };
+        let filename = JsonDirStore::pin_filename(&directory, &pin.id, revision);
+
+        std::fs::create_dir_all(&directory)?;
+
+        std::fs::write(filename, &pin_json)?;
+        std::fs::write(
+            JsonDirStore::latest_filename(&directory, &pin.id),
+            revision.to_string(),
+        )?;
+
+        Ok(())
+    }
+
+    fn get_all_pins(&self, username: &str) -> Result<Vec<Pin>, Error> {
+        let directory = self.pin_directory(username);
+
+        let mut pins: Vec<Pin> = JsonDirStore::latest_revisions(&directory)?
+            .iter()
+            .map(|(id, revision)| {
+                self.get_pin_from_filename(&JsonDirStore::pin_filename(&directory, id, *revision))
+            })
+            .collect::<Result<_, _>>()?;
+
+        pins.sort_by_key(|p| std::cmp::Reverse(p.created));
+
+        Ok(pins)
+    }
+
+    fn get_pin_revisions(&self, username: &str, id: &str) -> Result<Vec<(u32, Pin)>, Error> {
+        let directory = self.pin_directory(username);
+
+        let latest = match JsonDirStore::find_latest_revision(&directory, id) {
+            None => return Ok(vec![]),
+            Some(x) => x,
+        };
+
+        // Revisions might be missing if someone removed files by hand, so just skip those
+        (0..=latest)
+            .map(|revision| {
+                (
+                    revision,
+                    JsonDirStore::pin_filename(&directory, id, revision),
+                )
+            })
+            .filter(|(_, filename)| filename.exists())
+            .map(|(revision, filename)| Ok((revision, self.get_pin_from_filename(&filename)?)))
+            .collect()
+    }
+
+    fn trash_pin(&self, username: &str, id: &str) -> Result<(), Error> {
+        let trash_directory = self.trash_directory(username);
+
+        JsonDirStore::move_pin(&self.pin_directory(username), &trash_directory, id)?;
+
+        let trash_info = TrashInfo {
+            deleted: Utc::now(),
+        };
+        std::fs::write(
+            JsonDirStore::trash_info_filename(&trash_directory, id),
+            serde_json::to_string(&trash_info)?,
+        )?;
+
+        Ok(())
+    }
+
+    fn restore_pin(&self, username: &str, id: &str) -> Result<Pin, Error> {
+        let directory = self.pin_directory(username);
+        let trash_directory = self.trash_directory(username);
+
+        if JsonDirStore::find_latest_revision(&directory, id).is_some() {
+            bail!("Can't restore pin id {}, as it already exists", id);
+        }
+
+        JsonDirStore::move_pin(&trash_directory, &directory, id)?;
+
+        let trash_info_filename = JsonDirStore::trash_info_filename(&trash_directory, id);
+        if trash_info_filename.exists() {
+            std::fs::remove_file(trash_info_filename)?;
+        }
+
+        self.get_pin(username, id)
+    }
+
+    fn get_trashed_pins(&self, username: &str) -> Result<Vec<TrashedPin>, Error> {
+        let trash_directory = self.trash_directory(username);
+
+        let mut pins = vec![];
+        for (id, revision) in JsonDirStore::latest_revisions(&trash_directory)? {
+            let pin = self.get_pin_from_filename(&JsonDirStore::pin_filename(
+                &trash_directory,
+                &id,
+                revision,
+            ))?;
+
+            // Without the trash info file, the best we can do is assume the pin was deleted
+            // right after its last modification
+            let deleted = match std::fs::read_to_string(JsonDirStore::trash_info_filename(
+                &trash_directory,
+                &id,
+            )) {
+                Err(_) => pin.modified.unwrap_or(pin.created),
+                Ok(json_data) => serde_json::from_str::<TrashInfo>(&json_data)?.deleted,
+            };
+
+            pins.push(TrashedPin { pin, deleted });
+        }
+
+        pins.sort_by_key(|p| std::cmp::Reverse(p.deleted));
+
+        Ok(pins)
+    }
+
+    fn purge_pin(&self, username: &str, id: &str) -> Result<(), Error> {
+        let trash_directory = self.trash_directory(username);
+        let latest = JsonDirStore::latest_revision(&trash_directory, id)?;
+
+        for revision in 0..=latest {
+            let filename = JsonDirStore::pin_filename(&trash_directory, id, revision);
+            if filename.exists() {
+                std::fs::remove_file(filename)?;
+            }
+        }
+        let latest_filename = JsonDirStore::latest_filename(&trash_directory, id);
+        if latest_filename.exists() {
+            std::fs::remove_file(latest_filename)?;
+        }
+
+        let trash_info_filename = JsonDirStore::trash_info_filename(&trash_directory, id);
+        if trash_info_filename.exists() {
+            std::fs::remove_file(trash_info_filename)?;
+        }
+
+        Ok(())
+    }
+
+    fn watched_directory(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(id: &str, title: &str) -> Pin {
+        let mut pin = Pin::new();
+        pin.id = id.into();
+        pin.title = title.into();
+        pin
+    }
+
+    #[test]
+    fn latest_revision_is_recorded_on_save() {
+        crate::test_support::use_temp_directory();
+        let store = JsonDirStore::new("json_store_pins", "json_store_trash");
+        let username = "recorded";
+
+        store.add_pin(username, &pin("abc", "first")).unwrap();
+        store.add_pin(username, &pin("abc", "second")).unwrap();
+        assert_eq!(store.get_pin(username, "abc").unwrap().title, "second");
+        assert!(store.get_pin(username, "other").is_err());
+
+        store.trash_pin(username, "abc").unwrap();
+        assert!(store.get_pin(username, "abc").is_err());
+        assert_eq!(store.restore_pin(username, "abc").unwrap().title, "second");
+        store.add_pin(username, &pin("abc", "third")).unwrap();
+        let revisions = store.get_pin_revisions(username, "abc").unwrap();
+        assert_eq!(
+            revisions.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn pins_without_a_recorded_revision_are_found_whatever_the_gaps() {
+        crate::test_support::use_temp_directory();
+        let store = JsonDirStore::new("json_store_pins", "json_store_trash");
+        let username = "unrecorded";
+        let directory = store.pin_directory(username);
+        std::fs::create_dir_all(&directory).unwrap();
+
+        for (revision, title) in &[(0, "first"), (40, "latest")] {
+            std::fs::write(
+                JsonDirStore::pin_filename(&directory, "abc", *revision),
+                serde_json::to_string(&pin("abc", title)).unwrap(),
+            )
+            .unwrap();
+        }
+        assert_eq!(store.get_pin(username, "abc").unwrap().title, "latest");
+
+        store.add_pin(username, &pin("abc", "saved")).unwrap();
+        assert!(JsonDirStore::pin_filename(&directory, "abc", 41).exists());
+        assert_eq!(store.get_pin(username, "abc").unwrap().title, "saved");
+    }
+}
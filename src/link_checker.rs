@@ -0,0 +1,351 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::http_client::HttpClient;
+use crate::pins::Pin;
+
+/// Biggest body read when a site doesn't answer HEAD requests and has to be sent a GET
+const MAX_GET_SIZE: usize = 15 * 1024 * 1024;
+/// Failed checks in a row before a link that may just be down for a while is called broken
+const FAILURES_BEFORE_BROKEN: u32 = 2;
+/// Time before checking a link again after a failed check, instead of a whole interval
+const FAILED_CHECK_RETRY_HOURS: i64 = 6;
+
+/// Outcome of the latest check of a pin's url
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LinkStatus {
+    pub url: String,
+    pub checked: DateTime<Utc>,
+    /// Status code of the final response, if there was one at all
+    pub status: Option<u16>,
+    /// Where the url redirects to, if anywhere
+    pub redirect: Option<String>,
+    /// Where the url redirected to the first time it was checked, to notice it changing later
+    pub original_redirect: Option<String>,
+    pub redirect_changed: bool,
+    pub error: Option<String>,
+    /// Checks in a row that failed or got an error status, this one included
+    #[serde(default)]
+    pub failures: u32,
+    /// Whether the link looks dead, either because the site said the page is gone or because
+    /// it kept failing
+    pub broken: bool,
+}
+
+/// Statuses that mean a page is gone for good
+fn is_gone_status(status: u16) -> bool {
+    status == 404 || status == 410
+}
+
+/// Statuses that mean a page is gone or the site is having trouble, which may pass. Others, like
+/// 403 or 429, are often just sites turning away bots, so they don't count.
+fn is_dead_status(status: u16) -> bool {
+    is_gone_status(status) || status >= 500
+}
+
+/// Checks whether `url` still works. `previous` is the status of the last check of the same url,
+/// if any, which tells whether its redirect changed since.
+pub fn check_link(client: &dyn HttpClient, url: &str, previous: Option<&LinkStatus>) -> LinkStatus {
+    // Some servers don't implement HEAD, or implement it badly, so GET gets a chance too
+    let response = match client.head(url) {
+        Ok(response) if response.status != 405 && response.status != 501 => Ok(response),
+        _ => client.get(url, MAX_GET_SIZE),
+    };
+
+    let (status, redirect, error) = match response {
+        Err(err) => (None, None, Some(err.to_string())),
+        Ok(response) => {
+            let redirect = if response.url != url {
+                Some(response.url)
+            } else {
+                None
+            };
+            (Some(response.status), redirect, None)
+        }
+    };
+
+    let previous = previous.filter(|p| p.url == url);
+    let original_redirect = match previous {
+        Some(previous) => previous.original_redirect.clone(),
+        None => redirect.clone(),
+    };
+    // A failed check doesn't say anything about where the url redirects to
+    let redirect_changed = status.is_some() && redirect != original_redirect;
+
+    let failures = if error.is_some() || status.is_some_and(is_dead_status) {
+        previous.map(|p| p.failures).unwrap_or(0) + 1
+    } else {
+        0
+    };
+
+    LinkStatus {
+        url: url.to_string(),
+        checked: Utc::now(),
+        status,
+        broken: status.is_some_and(is_gone_status) || failures >= FAILURES_BEFORE_BROKEN,
+        failures,
+        redirect,
+        original_redirect,
+        redirect_changed,
+        error,
+    }
+}
+
+type StatusKey = (String, String);
+
+/// Keeps the link status of every pin, saved under `{root}/{username}/{pin_id}.json`
+#[derive(Clone)]
+pub struct LinkChecker {
+    root: PathBuf,
+    client: Arc<dyn HttpClient>,
+    /// Time after which a link gets checked again
+    interval: Duration,
+    statuses: Arc<Mutex<HashMap<StatusKey, LinkStatus>>>,
+}
+
+impl LinkChecker {
+    /// Opens the statuses saved in `root`. Requests are made through `client`, so tests can
+    /// point it anywhere.
+    pub fn open<P: AsRef<Path>>(
+        root: P,
+        client: Arc<dyn HttpClient>,
+        interval: Duration,
+    ) -> Result<LinkChecker, Error> {
+        let root = root.as_ref().to_path_buf();
+        let mut statuses = HashMap::new();
+
+        if root.exists() {
+            for user_directory in std::fs::read_dir(&root)? {
+                let user_directory = user_directory?.path();
+                let username = match user_directory.file_name().and_then(|f| f.to_str()) {
+                    Some(x) if user_directory.is_dir() => x.to_string(),
+                    _ => continue,
+                };
+
+                for file in std::fs::read_dir(&user_directory)? {
+                    let path = file?.path();
+                    let pin_id = match path.file_stem().and_then(|f| f.to_str()) {
+                        Some(x) if path.extension().is_some_and(|e| e == "json") => x.to_string(),
+                        _ => continue,
+                    };
+
+                    match std::fs::read_to_string(&path)
+                        .map_err(Error::from)
+                        .and_then(|json_data| Ok(serde_json::from_str::<LinkStatus>(&json_data)?))
+                    {
+                        Err(err) => error!("Could not read link status {:?}: {}", path, err),
+                        Ok(status) => {
+                            statuses.insert((username.clone(), pin_id), status);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(LinkChecker {
+            root,
+            client,
+            interval,
+            statuses: Arc::new(Mutex::new(statuses)),
+        })
+    }
+
+    fn status_filename(&self, username: &str, pin_id: &str) -> PathBuf {
+        self.root.join(username).join(format!("{}.json", pin_id))
+    }
+
+    pub fn get_status(&self, username: &str, pin_id: &str) -> Option<LinkStatus> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(&(username.to_string(), pin_id.to_string()))
+            .cloned()
+    }
+
+    /// Forgets the status of a pin, like when it's deleted
+    pub fn remove(&self, username: &str, pin_id: &str) -> Result<(), Error> {
+        let mut statuses = self.statuses.lock().unwrap();
+        statuses.remove(&(username.to_string(), pin_id.to_string()));
+
+        let filename = self.status_filename(username, pin_id);
+        if filename.exists() {
+            std::fs::remove_file(filename)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a pin's url hasn't been checked recently enough, or changed since. A link whose
+    /// last check failed is checked again sooner, to tell whether it's really broken.
+    pub fn is_due(&self, username: &str, pin: &Pin, now: DateTime<Utc>) -> bool {
+        let url = match pin.urls.first() {
+            Some(x) if !x.is_empty() => x,
+            _ => return false,
+        };
+
+        match self.get_status(username, &pin.id) {
+            None => true,
+            Some(status) => {
+                let interval = if status.failures > 0 && !status.broken {
+                    std::cmp::min(self.interval, Duration::hours(FAILED_CHECK_RETRY_HOURS))
+                } else {
+                    self.interval
+                };
+                status.url != *url || now - status.checked >= interval
+            }
+        }
+    }
+
+    /// Checks a pin's url and saves the outcome
+    pub fn check_pin(&self, username: &str, pin: &Pin) -> Result<LinkStatus, Error> {
+        let url = match pin.urls.first() {
+            Some(x) if !x.is_empty() => x,
+            _ => bail!("Pin {} has no url to check", pin.id),
+        };
+
+        // The lock isn't held during the request, which can take a while
+        let previous = self.get_status(username, &pin.id);
+        let status = check_link(self.client.as_ref(), url, previous.as_ref());
+
+        if status.broken {
+            info!(
+                "Link {} looks broken: {}",
+                url,
+                status
+                    .error
+                    .clone()
+                    .unwrap_or_else(|| format!("status {}", status.status.unwrap_or_default()))
+            );
+        }
+
+        let mut statuses = self.statuses.lock().unwrap();
+        let filename = self.status_filename(username, &pin.id);
+        std::fs::create_dir_all(filename.parent().unwrap())?;
+        std::fs::write(filename, serde_json::to_string(&status)?)?;
+        statuses.insert((username.to_string(), pin.id.clone()), status.clone());
+
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, FakeHttpClient};
+
+    const URL: &str = "http://example.com/page";
+
+    fn respond(client: &FakeHttpClient, method: &str, status: u16, final_url: &str) {
+        client.respond(method, URL, response(final_url, status, "text/html", b""));
+    }
+
+    #[test]
+    fn working_link() {
+        let client = FakeHttpClient::new();
+        respond(&client, "HEAD", 200, URL);
+
+        let status = check_link(&client, URL, None);
+        assert_eq!(status.status, Some(200));
+        assert_eq!(status.redirect, None);
+        assert!(!status.broken);
+        assert!(!status.redirect_changed);
+    }
+
+    #[test]
+    fn gone_pages_are_broken_at_once() {
+        for code in &[404, 410] {
+            let client = FakeHttpClient::new();
+            respond(&client, "HEAD", *code, URL);
+
+            let status = check_link(&client, URL, None);
+            assert_eq!(status.status, Some(*code));
+            assert!(status.broken);
+        }
+    }
+
+    #[test]
+    fn get_is_tried_when_head_is_not_allowed() {
+        let client = FakeHttpClient::new();
+        respond(&client, "HEAD", 405, URL);
+        respond(&client, "GET", 200, URL);
+
+        let status = check_link(&client, URL, None);
+        assert_eq!(status.status, Some(200));
+        assert!(!status.broken);
+
+        let methods: Vec<String> = client
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(method, _)| method.clone())
+            .collect();
+        assert_eq!(methods, vec!["HEAD", "GET"]);
+    }
+
+    #[test]
+    fn changed_redirects_are_noticed() {
+        let client = FakeHttpClient::new();
+        respond(&client, "HEAD", 200, "http://example.com/first");
+
+        let first = check_link(&client, URL, None);
+        assert_eq!(first.redirect.as_deref(), Some("http://example.com/first"));
+        assert!(!first.redirect_changed);
+
+        let again = check_link(&client, URL, Some(&first));
+        assert!(!again.redirect_changed);
+
+        respond(&client, "HEAD", 200, "http://example.com/parked");
+        let changed = check_link(&client, URL, Some(&again));
+        assert!(changed.redirect_changed);
+        assert_eq!(
+            changed.original_redirect.as_deref(),
+            Some("http://example.com/first")
+        );
+    }
+
+    #[test]
+    fn unreachable_links_are_broken_after_failing_twice() {
+        let client = FakeHttpClient::new();
+
+        let first = check_link(&client, URL, None);
+        assert_eq!(first.status, None);
+        assert!(first.error.is_some());
+        assert!(!first.broken);
+
+        let second = check_link(&client, URL, Some(&first));
+        assert_eq!(second.failures, 2);
+        assert!(second.broken);
+    }
+
+    #[test]
+    fn server_errors_are_broken_after_failing_twice() {
+        let client = FakeHttpClient::new();
+        respond(&client, "HEAD", 503, URL);
+        let first = check_link(&client, URL, None);
+        assert!(!first.broken);
+
+        respond(&client, "HEAD", 200, URL);
+        let recovered = check_link(&client, URL, Some(&first));
+        assert_eq!(recovered.failures, 0);
+
+        respond(&client, "HEAD", 500, URL);
+        let failed_again = check_link(&client, URL, Some(&recovered));
+        assert!(!failed_again.broken);
+        assert!(check_link(&client, URL, Some(&failed_again)).broken);
+    }
+
+    #[test]
+    fn failures_of_another_url_dont_count() {
+        let client = FakeHttpClient::new();
+        let mut previous = check_link(&client, "http://example.com/old", None);
+        previous = check_link(&client, "http://example.com/old", Some(&previous));
+        assert!(previous.broken);
+
+        assert!(!check_link(&client, URL, Some(&previous)).broken);
+    }
+}
@@ -2,6 +2,7 @@ extern crate actix_web;
 extern crate argon2rs;
 extern crate chrono;
 extern crate fern;
+extern crate futures;
 extern crate html5ever;
 extern crate image;
 extern crate pulldown_cmark;
@@ -23,15 +24,43 @@ use actix_web::middleware::{identity::RequestIdentity, Logger};
 use actix_web::{fs::NamedFile, http, server, App, Form, HttpRequest, Responder, State};
 //use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::rc::Rc;
 
+mod cache;
+mod config;
+mod csv_bookmarks;
+mod download_queue;
 mod downloader;
 mod errors;
+mod fulltext;
 mod htmlrenderer;
+mod http_client;
+mod importer;
+mod json_store;
+mod link_checker;
+mod netscape_bookmarks;
+mod page_inliner;
+mod page_metadata;
+mod page_renderer;
+mod pin_history;
+mod pin_index;
+mod pin_redirects;
+mod pinboard;
 mod pins;
+mod reader_mode;
+mod search_query;
+mod snapshots;
+mod sqlite_store;
+#[cfg(test)]
+mod test_support;
+mod thumbnail;
+mod url_normalizer;
+mod url_resolver;
 mod user;
 mod user_archive;
+mod warc;
 use pins::*;
 
 struct AppState {
@@ -40,9 +69,9 @@ struct AppState {
 }
 
 impl AppState {
-    fn new() -> AppState {
+    fn new(storage: BackingStore) -> AppState {
         AppState {
-            storage: BackingStore::new(),
+            storage,
             html_renderer: Rc::new(htmlrenderer::HTMLRenderer::new()),
         }
     }
@@ -71,12 +100,21 @@ fn is_logged_in(req: &HttpRequest<AppState>) -> bool {
     }
 }
 
+/// Splits the urls field of a form, which can hold any number of urls separated by whitespace
+fn parse_urls(urls: &str) -> Vec<String> {
+    urls.split_whitespace().map(String::from).collect()
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct PinInfo {
     title: Option<String>,
     url: Option<String>,
     description: Option<String>,
     tags: Option<String>,
+    /// Set once the user was told about pins with the same url and chose to add it anyway
+    allow_duplicate: Option<String>,
+    /// Pin to add this one's urls, tags and description to, instead of saving a new one
+    merge_into: Option<String>,
 }
 
 fn add_pin(
@@ -84,7 +122,7 @@ fn add_pin(
     state: State<AppState>,
     pin_info: Form<PinInfo>,
 ) -> impl Responder {
-    if req.identity() == None {
+    if req.identity().is_none() {
         error!("add_pin reached without a proper identity");
         return actix_web::HttpResponse::Forbidden().finish();
     }
@@ -95,33 +133,37 @@ fn add_pin(
     if let Some(title) = pin_info.title {
         pin.title = title;
     }
-    if let Some(url) = pin_info.url {
-        pin.urls = vec![url];
+    if let Some(urls) = pin_info.url {
+        pin.urls = parse_urls(&urls);
     }
     if let Some(description) = pin_info.description {
         pin.description = description;
     }
     if let Some(tags) = pin_info.tags {
-        pin.tags = tags
-            .chars()
-            .map(|c| c.to_lowercase().collect::<String>())
-            .collect::<String>()
-            .chars()
-            // I believe we could potentially support weird chars, but this'll do for now
-            .filter(|c| c.is_alphanumeric() || c.is_whitespace() || *c == '_')
-            .collect::<String>()
-            .split_whitespace()
-            .filter(|x| !x.is_empty())
-            .map(String::from)
-            .collect();
-
-        // Deduplicate tags
-        pin.tags.sort_unstable();
-        pin.tags.dedup();
+        pin.tags = parse_tags(&tags);
     }
 
-    if let Err(err) = state.storage.add_pin(req.identity().unwrap(), pin) {
-        error!("Err: {:?}", err);
+    let username = req.identity().unwrap();
+
+    if let Some(id) = pin_info.merge_into.filter(|id| !id.is_empty()) {
+        if let Err(err) = state.storage.merge_into_pin(&username, &id, &pin) {
+            error!("Err: {:?}", err);
+        }
+    } else {
+        if pin_info.allow_duplicate.is_none() {
+            match state.storage.find_duplicates_of(&username, &pin.urls) {
+                Err(err) => error!("Err: {:?}", err),
+                Ok(duplicates) => {
+                    if !duplicates.is_empty() {
+                        return duplicate_pin_page(&req, &pin, &duplicates);
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = state.storage.add_pin(username, pin) {
+            error!("Err: {:?}", err);
+        }
     }
 
     actix_web::HttpResponse::SeeOther()
@@ -129,11 +171,84 @@ fn add_pin(
         .finish()
 }
 
+/// Asks what to do with a new pin whose url was already saved: add it to one of the existing
+/// pins, or save it anyway
+fn duplicate_pin_page(
+    req: &HttpRequest<AppState>,
+    pin: &Pin,
+    duplicates: &[Pin],
+) -> actix_web::HttpResponse {
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let page_data = json!({
+        "pin": pin,
+        "urls": pin.urls.join(" "),
+        "tags": pin.tags.join(" "),
+        "duplicates": duplicates,
+        "duplicate_count": duplicates.len(),
+        "logged_in": true,
+        "theme": extract_theme(req),
+    });
+
+    let contents = match renderer.render_page("duplicate_pin", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
+/// Lists the pins sharing an url with other pins
+fn duplicates_page(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
+    let username = req.identity().unwrap_or_default();
+
+    if username.is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let groups = match req.state().storage.find_duplicates(&username) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    let page_data = json!({
+        "groups": groups,
+        "logged_in": true,
+        "theme": extract_theme(&req),
+    });
+
+    let contents = match renderer.render_page("duplicates", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
 fn delete_pin(
     req: HttpRequest<AppState>,
     path: actix_web::Path<String>,
 ) -> actix_web::HttpResponse {
-    if req.identity() == None {
+    if req.identity().is_none() {
         error!("add_pin reached without a proper identity");
         return actix_web::HttpResponse::Forbidden().finish();
     }
@@ -150,6 +265,83 @@ fn delete_pin(
         .finish()
 }
 
+fn restore_pin(
+    req: HttpRequest<AppState>,
+    path: actix_web::Path<String>,
+) -> actix_web::HttpResponse {
+    if req.identity().is_none() {
+        error!("restore_pin reached without a proper identity");
+        return actix_web::HttpResponse::Forbidden().finish();
+    }
+
+    let pin_id = path;
+
+    let username = req.identity().unwrap();
+    if let Err(err) = req.state().storage.restore_pin(&username, &pin_id) {
+        error!("Err: {:?}", err);
+    }
+
+    actix_web::HttpResponse::SeeOther()
+        .header(actix_web::http::header::LOCATION, "/trash")
+        .finish()
+}
+
+fn empty_trash(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
+    if req.identity().is_none() {
+        error!("empty_trash reached without a proper identity");
+        return actix_web::HttpResponse::Forbidden().finish();
+    }
+
+    let username = req.identity().unwrap();
+    if let Err(err) = req.state().storage.empty_trash(&username) {
+        error!("Err: {:?}", err);
+    }
+
+    actix_web::HttpResponse::SeeOther()
+        .header(actix_web::http::header::LOCATION, "/trash")
+        .finish()
+}
+
+fn trash_page(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
+    let username = req.identity().unwrap_or_default();
+
+    if username.is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let trashed_pins = match req.state().storage.get_trashed_pins(&username) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    let page_data = json!({
+        "trashed_pins": trashed_pins,
+        "retention_days": req.state().storage.trash_retention_days(),
+        "logged_in": true,
+        "theme": extract_theme(&req),
+    });
+
+    let contents = match renderer.render_page("trash", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct EditPinInfo {
     id: String,
@@ -157,6 +349,7 @@ struct EditPinInfo {
     url: Option<String>,
     description: Option<String>,
     tags: Option<String>,
+    rearchive_days: Option<String>,
 }
 
 fn edit_pin_data(
@@ -164,45 +357,42 @@ fn edit_pin_data(
     state: State<AppState>,
     pin_info: Form<EditPinInfo>,
 ) -> impl Responder {
-    if req.identity() == None {
+    if req.identity().is_none() {
         error!("edit_pin reached without a proper identity");
         return actix_web::HttpResponse::Forbidden().finish();
     }
 
+    let username = req.identity().unwrap();
     let pin_info = pin_info.into_inner();
-    let mut pin = Pin::new();
 
-    pin.id = pin_info.id;
+    // Only the fields in the form change, the rest of the pin is kept as it was
+    let mut pin = match state.storage.get_pin(&username, &pin_info.id) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::NotFound().finish();
+        }
+        Ok(x) => x,
+    };
 
     if let Some(title) = pin_info.title {
         pin.title = title;
     }
-    if let Some(url) = pin_info.url {
-        pin.urls = vec![url];
+    if let Some(urls) = pin_info.url {
+        pin.urls = parse_urls(&urls);
     }
     if let Some(description) = pin_info.description {
         pin.description = description;
     }
     if let Some(tags) = pin_info.tags {
-        pin.tags = tags
-            .chars()
-            .map(|c| c.to_lowercase().collect::<String>())
-            .collect::<String>()
-            .chars()
-            // I believe we could potentially support weird chars, but this'll do for now
-            .filter(|c| c.is_alphanumeric() || c.is_whitespace() || *c == '_')
-            .collect::<String>()
-            .split_whitespace()
-            .filter(|x| !x.is_empty())
-            .map(String::from)
-            .collect();
-
-        // Deduplicate tags
-        pin.tags.sort_unstable();
-        pin.tags.dedup();
+        pin.tags = parse_tags(&tags);
     }
+    // Left empty, or anything that isn't a number of days, means no periodic captures
+    pin.rearchive_days = pin_info
+        .rearchive_days
+        .and_then(|days| days.trim().parse().ok())
+        .filter(|days| *days > 0);
 
-    if let Err(err) = state.storage.add_pin(req.identity().unwrap(), pin) {
+    if let Err(err) = state.storage.add_pin(username, pin) {
         error!("Err: {:?}", err);
     }
 
@@ -236,7 +426,7 @@ fn index(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
 
     let username = req.identity().unwrap_or_default();
 
-    if username == "" {
+    if username.is_empty() {
         return login_screen(req.state());
     }
 
@@ -246,13 +436,20 @@ fn index(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
     let query = req.query();
 
     let search_query = query.get("search");
+    let mut search_error = None;
     let pins = if let Some(search_query) = search_query {
-        match req.state().storage.search_pins(&username, &search_query) {
+        match search_query::SearchQuery::parse(search_query) {
             Err(err) => {
-                error!("Err: {:?}", err);
-                return actix_web::HttpResponse::InternalServerError().finish();
+                search_error = Some(err.to_string());
+                vec![]
             }
-            Ok(x) => x,
+            Ok(parsed_query) => match req.state().storage.search_pins(&username, &parsed_query) {
+                Err(err) => {
+                    error!("Err: {:?}", err);
+                    return actix_web::HttpResponse::InternalServerError().finish();
+                }
+                Ok(x) => x,
+            },
         }
     } else {
         match req.state().storage.get_all_pins(&username) {
@@ -260,7 +457,10 @@ fn index(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
                 error!("Err: {:?}", err);
                 return actix_web::HttpResponse::InternalServerError().finish();
             }
-            Ok(x) => x,
+            Ok(x) => x
+                .into_iter()
+                .map(|pin| req.state().storage.search_result(&username, pin, None))
+                .collect(),
         }
     };
 
@@ -299,11 +499,26 @@ fn index(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
     let pin_count = pins.len();
     let current_theme = extract_theme(&req);
 
+    // Searching for a single tag offers to capture its pins periodically
+    let rearchive_tag = search_query
+        .and_then(|q| q.trim().strip_prefix("tag:"))
+        .filter(|tag| !tag.is_empty() && !tag.contains(char::is_whitespace))
+        .map(|tag| {
+            let tag = tag.to_lowercase();
+            let days = snapshots::get_tag_intervals(&username)
+                .unwrap_or_default()
+                .get(&tag)
+                .cloned();
+            json!({ "tag": tag, "days": days })
+        });
+
     let index_data = json!({
         "username": username,
         "pins": pins,
         "pin_count": pin_count,
         "search_term": search_query.unwrap_or(&String::new()),
+        "search_error": search_error,
+        "rearchive_tag": rearchive_tag,
         "tags": tags,
         "logged_in": true,
         "elapsed_time": format!("{:.3}", chrono::Local::now().signed_duration_since(time_start).num_nanoseconds().unwrap() as f32 / 1e6f32),
@@ -391,16 +606,309 @@ fn static_files(req: HttpRequest<AppState>) -> actix_web::Result<NamedFile> {
     ))?)
 }
 
+/// Downloads the pins matching a search, or every pin, as a `bookmarks.html` file browsers can
+/// import
+fn export_bookmarks(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
+    let username = req.identity().unwrap_or_default();
+
+    if username.is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    let query = req.query();
+    let pins = match query.get("search").filter(|s| !s.trim().is_empty()) {
+        Some(search) => match search_query::SearchQuery::parse(search) {
+            Err(err) => {
+                return actix_web::HttpResponse::BadRequest()
+                    .body(format!("Invalid search \"{}\": {}", search, err));
+            }
+            Ok(parsed_query) => req
+                .state()
+                .storage
+                .search_pins(&username, &parsed_query)
+                .map(|results| results.into_iter().map(|r| r.pin).collect()),
+        },
+        None => req.state().storage.get_all_pins(&username),
+    };
+    let pins = match pins {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    let bookmarks = netscape_bookmarks::write_bookmarks(&pins, query.contains_key("folders"));
+
+    use actix_web::http::header::*;
+
+    let content_disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Iso_8859_1,
+            language_tag: None,
+            value: b"bookmarks.html".to_vec(),
+        })],
+    };
+
+    actix_web::HttpResponse::Ok()
+        .header(CONTENT_DISPOSITION, content_disposition)
+        .content_type("text/html; charset=utf-8")
+        .body(bookmarks)
+}
+
+/// Biggest file accepted for importing bookmarks
+const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Biggest user archive accepted for restoring an account, as they hold cached pages too
+const MAX_ARCHIVE_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Reads every field of an uploaded multipart form into memory, failing once one of them gets
+/// bigger than `max_size`
+fn read_multipart_fields(
+    req: &HttpRequest<AppState>,
+    max_size: usize,
+) -> impl futures::Future<Item = HashMap<String, Vec<u8>>, Error = actix_web::Error> {
+    use actix_web::multipart::MultipartItem;
+    use actix_web::HttpMessage;
+    use futures::{future, Future, Stream};
+
+    req.multipart()
+        .map_err(actix_web::Error::from)
+        .and_then(
+            move |item| -> Box<
+                dyn Future<Item = Option<(String, Vec<u8>)>, Error = actix_web::Error>,
+            > {
+                match item {
+                    MultipartItem::Field(field) => {
+                        let name = field
+                            .content_disposition()
+                            .and_then(|d| d.get_name().map(String::from))
+                            .unwrap_or_default();
+
+                        Box::new(
+                            field
+                                .map_err(actix_web::Error::from)
+                                .fold(vec![], move |mut data, chunk| {
+                                    data.extend_from_slice(&chunk);
+                                    if data.len() > max_size {
+                                        Err(actix_web::error::ErrorPayloadTooLarge(
+                                            "Uploaded file is too big",
+                                        ))
+                                    } else {
+                                        Ok(data)
+                                    }
+                                })
+                                .map(move |data| Some((name, data))),
+                        )
+                    }
+                    // Browsers don't send nested forms
+                    MultipartItem::Nested(_) => Box::new(future::ok(None)),
+                }
+            },
+        )
+        .fold(HashMap::new(), |mut fields, field| {
+            if let Some((name, data)) = field {
+                fields.insert(name, data);
+            }
+            Ok::<_, actix_web::Error>(fields)
+        })
+}
+
+fn render_import_page(
+    req: &HttpRequest<AppState>,
+    summary: Option<&importer::ImportSummary>,
+    error: Option<String>,
+) -> actix_web::HttpResponse {
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let page_data = json!({
+        "summary": summary,
+        "skipped_count": summary.map(|s| s.skipped_duplicates.len()),
+        "failed_count": summary.map(|s| s.failed.len()),
+        "import_error": error,
+        "logged_in": true,
+        "theme": extract_theme(req),
+    });
+
+    let contents = match renderer.render_page("import", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
+fn import_page(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
+    if req.identity().unwrap_or_default().is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    render_import_page(&req, None, None)
+}
+
+/// Runs slow work, like a big import, on a thread of its own so the server keeps answering other
+/// requests meanwhile
+fn run_in_background<T, F>(f: F) -> impl futures::Future<Item = T, Error = actix_web::Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    use futures::Future;
+
+    let (tx, rx) = futures::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+
+    rx.map_err(|_| actix_web::error::ErrorInternalServerError("Background work stopped early"))
+}
+
+/// Page showing how an import went
+fn render_import_result(
+    req: &HttpRequest<AppState>,
+    result: Result<importer::ImportSummary, failure::Error>,
+) -> actix_web::HttpResponse {
+    match result {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            actix_web::HttpResponse::InternalServerError().finish()
+        }
+        Ok(summary) => render_import_page(req, Some(&summary), None),
+    }
+}
+
+/// Adds a pin for every bookmark in an uploaded export from a browser or another bookmarking
+/// service. A dry run only shows what would be added, and keeps the bookmarks until the import
+/// is confirmed.
+fn import_bookmarks(
+    req: HttpRequest<AppState>,
+) -> actix_web::FutureResponse<actix_web::HttpResponse> {
+    use futures::{future, Future};
+
+    let username = req.identity().unwrap_or_default();
+    if username.is_empty() {
+        error!("import_bookmarks reached without a proper identity");
+        return Box::new(future::ok(actix_web::HttpResponse::Forbidden().finish()));
+    }
+
+    Box::new(read_multipart_fields(&req, MAX_IMPORT_SIZE).and_then(
+        move |fields| -> actix_web::FutureResponse<actix_web::HttpResponse> {
+            let file = match fields.get("bookmarks") {
+                Some(x) if !x.is_empty() => x,
+                _ => {
+                    return Box::new(future::ok(render_import_page(
+                        &req,
+                        None,
+                        Some("No file was uploaded".into()),
+                    )))
+                }
+            };
+            let field = |name: &str| {
+                fields
+                    .get(name)
+                    .map(|data| String::from_utf8_lossy(data).into_owned())
+                    .unwrap_or_default()
+            };
+
+            let columns = csv_bookmarks::CsvColumns {
+                url: field("url_column"),
+                title: field("title_column"),
+                description: field("description_column"),
+                tags: field("tags_column"),
+                created: field("created_column"),
+                tag_separator: field("tag_separator"),
+            };
+            let bookmarks = match importer::parse_export(
+                &field("format"),
+                &String::from_utf8_lossy(file),
+                &columns,
+            ) {
+                Err(err) => {
+                    return Box::new(future::ok(render_import_page(
+                        &req,
+                        None,
+                        Some(err.to_string()),
+                    )))
+                }
+                Ok(x) => x,
+            };
+
+            let dry_run = fields.contains_key("dry_run");
+            if dry_run {
+                if let Err(err) = importer::save_pending_import(&username, &bookmarks) {
+                    error!("Err: {:?}", err);
+                    return Box::new(future::ok(
+                        actix_web::HttpResponse::InternalServerError().finish(),
+                    ));
+                }
+            }
+
+            let storage = req.state().storage.clone();
+            Box::new(
+                run_in_background(move || storage.import_bookmarks(&username, &bookmarks, dry_run))
+                    .map(move |result| render_import_result(&req, result)),
+            )
+        },
+    ))
+}
+
+/// Imports the bookmarks previewed by the latest dry run
+fn confirm_import(
+    req: HttpRequest<AppState>,
+) -> actix_web::FutureResponse<actix_web::HttpResponse> {
+    use futures::{future, Future};
+
+    let username = req.identity().unwrap_or_default();
+    if username.is_empty() {
+        error!("confirm_import reached without a proper identity");
+        return Box::new(future::ok(actix_web::HttpResponse::Forbidden().finish()));
+    }
+
+    let bookmarks = match importer::take_pending_import(&username) {
+        Err(err) => {
+            return Box::new(future::ok(render_import_page(
+                &req,
+                None,
+                Some(err.to_string()),
+            )))
+        }
+        Ok(x) => x,
+    };
+
+    let storage = req.state().storage.clone();
+    Box::new(
+        run_in_background(move || storage.import_bookmarks(&username, &bookmarks, false))
+            .map(move |result| render_import_result(&req, result)),
+    )
+}
+
+/// Sends a zip file with all of a user's data, with `?cache=true` their cached pages too
 fn download_archive(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
     let username = req.identity().unwrap_or_default();
 
-    if username == "" {
+    if username.is_empty() {
         return actix_web::HttpResponse::SeeOther()
             .header(actix_web::http::header::LOCATION, "/")
             .finish();
     }
 
-    let zip_file_contents = match user_archive::generate_archive_for_user(username) {
+    let include_cache = req.query().get("cache").is_some_and(|c| c == "true");
+    let archive = match user_archive::generate_archive_for_user(
+        &req.state().storage,
+        &username,
+        include_cache,
+    ) {
         Ok(x) => x,
         Err(err) => {
             error!("Err: {:?}", err);
@@ -419,14 +927,95 @@ fn download_archive(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
         })],
     };
 
+    // The archive is sent a chunk at a time while it's written, rather than kept in memory
     actix_web::HttpResponse::Ok()
-        .header(
-            actix_web::http::header::CONTENT_DISPOSITION,
-            content_disposition,
-        )
         .content_type("application/zip")
-        .content_length(zip_file_contents.len() as u64)
-        .body(zip_file_contents)
+        .header(CONTENT_DISPOSITION, content_disposition)
+        .streaming(archive)
+}
+
+fn render_restore_page(
+    req: &HttpRequest<AppState>,
+    summary: Option<&user_archive::RestoreSummary>,
+    error: Option<String>,
+) -> actix_web::HttpResponse {
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let page_data = json!({
+        "summary": summary,
+        "restore_error": error,
+        "logged_in": true,
+        "theme": extract_theme(req),
+    });
+
+    let contents = match renderer.render_page("restore", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
+fn restore_page(req: HttpRequest<AppState>) -> actix_web::HttpResponse {
+    if req.identity().unwrap_or_default().is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    render_restore_page(&req, None, None)
+}
+
+/// Restores pins from an uploaded user archive, next to the current ones or in their place
+fn restore_archive(
+    req: HttpRequest<AppState>,
+) -> actix_web::FutureResponse<actix_web::HttpResponse> {
+    use futures::{future, Future};
+
+    let username = req.identity().unwrap_or_default();
+    if username.is_empty() {
+        error!("restore_archive reached without a proper identity");
+        return Box::new(future::ok(actix_web::HttpResponse::Forbidden().finish()));
+    }
+
+    Box::new(read_multipart_fields(&req, MAX_ARCHIVE_SIZE).and_then(
+        move |mut fields| -> actix_web::FutureResponse<actix_web::HttpResponse> {
+            let archive = match fields.remove("archive") {
+                Some(x) if !x.is_empty() => x,
+                _ => {
+                    return Box::new(future::ok(render_restore_page(
+                        &req,
+                        None,
+                        Some("No file was uploaded".into()),
+                    )))
+                }
+            };
+            let mode = match fields.get("mode").map(|m| &m[..]) {
+                Some(b"replace") => user_archive::RestoreMode::Replace,
+                _ => user_archive::RestoreMode::Merge,
+            };
+
+            let storage = req.state().storage.clone();
+            Box::new(
+                run_in_background(move || {
+                    user_archive::restore_archive(&storage, &username, &archive, mode)
+                })
+                .map(move |result| match result {
+                    Err(err) => {
+                        error!("Err: {:?}", err);
+                        render_restore_page(&req, None, Some(err.to_string()))
+                    }
+                    Ok(summary) => render_restore_page(&req, Some(&summary), None),
+                }),
+            )
+        },
+    ))
 }
 
 fn page_cache(req: HttpRequest<AppState>) -> actix_web::Result<NamedFile> {
@@ -444,13 +1033,71 @@ fn page_cache(req: HttpRequest<AppState>) -> actix_web::Result<NamedFile> {
     ))?)
 }
 
+/// Answers a request for a page of a pin that doesn't exist. Pins merged into another one lead
+/// to the same page of that pin, so old links keep working.
+fn missing_pin_response(
+    req: &HttpRequest<AppState>,
+    username: &str,
+    pin_id: &str,
+    page: &str,
+) -> actix_web::HttpResponse {
+    match req.state().storage.merged_into(username, pin_id) {
+        Some(id) => actix_web::HttpResponse::SeeOther()
+            .header(
+                actix_web::http::header::LOCATION,
+                format!("/{}/{}", page, id),
+            )
+            .finish(),
+        None => actix_web::HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct MergeInfo {
+    /// Ids of the pins to merge, separated by whitespace
+    ids: String,
+    /// Pin the others are merged into, the first of `ids` if not set
+    into: Option<String>,
+}
+
+fn merge_pins(
+    req: HttpRequest<AppState>,
+    state: State<AppState>,
+    merge_info: Form<MergeInfo>,
+) -> impl Responder {
+    if req.identity().is_none() {
+        error!("merge_pins reached without a proper identity");
+        return actix_web::HttpResponse::Forbidden().finish();
+    }
+
+    let merge_info = merge_info.into_inner();
+    let mut ids: Vec<String> = merge_info
+        .ids
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    if let Some(into) = merge_info.into.filter(|id| !id.is_empty()) {
+        ids.retain(|id| *id != into);
+        ids.insert(0, into);
+    }
+
+    let username = req.identity().unwrap();
+    if let Err(err) = state.storage.merge_pins(&username, &ids) {
+        error!("Err: {:?}", err);
+    }
+
+    actix_web::HttpResponse::SeeOther()
+        .header(actix_web::http::header::LOCATION, "/duplicates")
+        .finish()
+}
+
 fn edit_pin_page(
     req: HttpRequest<AppState>,
     path: actix_web::Path<String>,
 ) -> actix_web::HttpResponse {
     let username = req.identity().unwrap_or_default();
 
-    if username == "" {
+    if username.is_empty() {
         return actix_web::HttpResponse::SeeOther()
             .header(actix_web::http::header::LOCATION, "/")
             .finish();
@@ -464,7 +1111,7 @@ fn edit_pin_page(
     let pin = match req.state().storage.get_pin(&username, &pin_id) {
         Err(err) => {
             error!("Err: {:?}", err);
-            return actix_web::HttpResponse::NotFound().finish();
+            return missing_pin_response(&req, &username, &pin_id, "edit");
         }
         Ok(x) => x,
     };
@@ -492,6 +1139,279 @@ fn edit_pin_page(
         .body(contents)
 }
 
+/// Shows a pin's page in reader mode, with only the article text. Notes without an url show
+/// their description instead.
+fn view_pin_page(
+    req: HttpRequest<AppState>,
+    path: actix_web::Path<String>,
+) -> actix_web::HttpResponse {
+    let username = req.identity().unwrap_or_default();
+
+    if username.is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let pin_id = path;
+
+    let pin = match req.state().storage.get_pin(&username, &pin_id) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return missing_pin_response(&req, &username, &pin_id, "view");
+        }
+        Ok(x) => x,
+    };
+
+    let is_note = pin.urls.iter().all(|url| url.is_empty());
+    let article = if is_note {
+        None
+    } else {
+        let cached_page = cache::cache_directory(&username).join(format!("{}.html", pin.id));
+        std::fs::read(cached_page)
+            .ok()
+            .and_then(|html| reader_mode::extract_article(&html))
+    };
+
+    let page_data = json!({
+        "username": username,
+        "pin": pin,
+        "is_note": is_note,
+        "article": article,
+        "has_cached_page": cache::has_cached_page(&username, &pin.id),
+        "logged_in": true,
+        "theme": extract_theme(&req),
+    });
+
+    let contents = match renderer.render_page("view", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
+fn pin_history_page(
+    req: HttpRequest<AppState>,
+    path: actix_web::Path<String>,
+) -> actix_web::HttpResponse {
+    let username = req.identity().unwrap_or_default();
+
+    if username.is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let pin_id = path;
+
+    let revisions = match req.state().storage.get_pin_revisions(&username, &pin_id) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    if revisions.is_empty() {
+        return missing_pin_response(&req, &username, &pin_id, "history");
+    }
+
+    let page_data = json!({
+        "pin_id": *pin_id,
+        "revisions": pin_history::build_history(revisions),
+        "logged_in": true,
+        "theme": extract_theme(&req),
+    });
+
+    let contents = match renderer.render_page("pin_history", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
+#[derive(Serialize)]
+struct TimelineEntry {
+    #[serde(flatten)]
+    snapshot: snapshots::Snapshot,
+    /// Whether the entry is picked as either side of the diff
+    is_from: bool,
+    is_to: bool,
+}
+
+/// Shows every snapshot of a pin's page, and the text diff between two of them, picked with
+/// the `from` and `to` query parameters
+fn snapshots_page(
+    req: HttpRequest<AppState>,
+    path: actix_web::Path<String>,
+) -> actix_web::HttpResponse {
+    let username = req.identity().unwrap_or_default();
+
+    if username.is_empty() {
+        return actix_web::HttpResponse::SeeOther()
+            .header(actix_web::http::header::LOCATION, "/")
+            .finish();
+    }
+
+    use std::borrow::Borrow;
+    let renderer: &htmlrenderer::HTMLRenderer = req.state().html_renderer.borrow();
+
+    let pin_id = path;
+
+    let pin = match req.state().storage.get_pin(&username, &pin_id) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return missing_pin_response(&req, &username, &pin_id, "snapshots");
+        }
+        Ok(x) => x,
+    };
+
+    let mut timeline = match snapshots::get_timeline(&username, &pin.id) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+    timeline.reverse();
+
+    // Without a choice, the latest change is shown
+    let query = req.query();
+    let to = query
+        .get("to")
+        .cloned()
+        .or_else(|| timeline.first().map(|s| s.hash.clone()));
+    let from = query
+        .get("from")
+        .cloned()
+        .or_else(|| timeline.get(1).map(|s| s.hash.clone()));
+
+    let diff = match (&from, &to) {
+        (Some(from), Some(to)) if from != to => {
+            match snapshots::diff_snapshots(&username, &pin.id, from, to) {
+                Err(err) => {
+                    error!("Err: {:?}", err);
+                    return actix_web::HttpResponse::NotFound().finish();
+                }
+                Ok(x) => Some(x),
+            }
+        }
+        _ => None,
+    };
+
+    let entries: Vec<TimelineEntry> = timeline
+        .into_iter()
+        .map(|snapshot| TimelineEntry {
+            is_from: from.as_ref() == Some(&snapshot.hash),
+            is_to: to.as_ref() == Some(&snapshot.hash),
+            snapshot,
+        })
+        .collect();
+
+    let page_data = json!({
+        "username": username,
+        "pin": pin,
+        "snapshots": entries,
+        "diff": diff,
+        "logged_in": true,
+        "theme": extract_theme(&req),
+    });
+
+    let contents = match renderer.render_page("snapshots", &page_data) {
+        Err(err) => {
+            error!("Err: {:?}", err);
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+        Ok(x) => x,
+    };
+
+    actix_web::HttpResponse::Ok()
+        .content_type("text/html")
+        .body(contents)
+}
+
+#[derive(Deserialize)]
+struct RearchiveTagInfo {
+    tag: String,
+    days: Option<String>,
+}
+
+fn rearchive_tag(req: HttpRequest<AppState>, form: Form<RearchiveTagInfo>) -> impl Responder {
+    let username = match req.identity() {
+        None => {
+            error!("rearchive_tag reached without a proper identity");
+            return actix_web::HttpResponse::Forbidden().finish();
+        }
+        Some(x) => x,
+    };
+
+    let days = form
+        .days
+        .as_ref()
+        .and_then(|days| days.trim().parse().ok())
+        .unwrap_or(0);
+
+    if let Err(err) = snapshots::set_tag_interval(&username, &form.tag, days) {
+        error!("Err: {:?}", err);
+        return actix_web::HttpResponse::InternalServerError().finish();
+    }
+
+    let location = format!(
+        "/?search={}",
+        url::form_urlencoded::byte_serialize(format!("tag:{}", form.tag).as_bytes())
+            .collect::<String>()
+    );
+    actix_web::HttpResponse::SeeOther()
+        .header(actix_web::http::header::LOCATION, location)
+        .finish()
+}
+
+fn restore_revision(
+    req: HttpRequest<AppState>,
+    path: actix_web::Path<(String, u32)>,
+) -> actix_web::HttpResponse {
+    if req.identity().is_none() {
+        error!("restore_revision reached without a proper identity");
+        return actix_web::HttpResponse::Forbidden().finish();
+    }
+
+    let (pin_id, revision) = path.into_inner();
+
+    let username = req.identity().unwrap();
+    if let Err(err) = req
+        .state()
+        .storage
+        .restore_revision(&username, &pin_id, revision)
+    {
+        error!("Err: {:?}", err);
+    }
+
+    actix_web::HttpResponse::SeeOther()
+        .header(
+            actix_web::http::header::LOCATION,
+            format!("/history/{}", pin_id),
+        )
+        .finish()
+}
+
 #[derive(Deserialize)]
 struct SignupInfo {
     username: String,
@@ -585,13 +1505,13 @@ fn generate_cookie_key(filename: &str) -> Vec<u8> {
         use rand_pcg::rand_core::RngCore;
         let mut cookie_key = vec![0u8; 32];
 
-        let timestamp = chrono::Utc::now().timestamp_nanos();
+        let timestamp = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
         rand_pcg::Mcg128Xsl64::new(0x1337_f00d_d15e_a5e5u128 + timestamp as u128)
             .fill_bytes(&mut cookie_key);
         cookie_key
     };
 
-    let mut f = std::fs::File::create(&filename).unwrap();
+    let mut f = std::fs::File::create(filename).unwrap();
 
     use std::io::Write;
     f.write_all(&cookie_key).unwrap();
@@ -603,20 +1523,20 @@ fn get_cookie_key() -> Vec<u8> {
     let filename = "auth_cookie_key.bin";
 
     if !std::path::Path::new(filename).exists() {
-        return generate_cookie_key(&filename);
+        return generate_cookie_key(filename);
     }
 
-    let f = std::fs::File::open(&filename);
+    let f = std::fs::File::open(filename);
 
     if f.is_err() {
-        return generate_cookie_key(&filename);
+        return generate_cookie_key(filename);
     }
 
     use std::io::Read;
 
     let mut result = Vec::new();
     if f.unwrap().read_to_end(&mut result).is_err() {
-        return generate_cookie_key(&filename);
+        return generate_cookie_key(filename);
     }
 
     result
@@ -644,10 +1564,13 @@ fn main() {
         .apply()
         .unwrap();
 
+    let config = config::Config::load("recense_config.json").unwrap();
+    let storage = BackingStore::new(&config).unwrap();
+
     let cookie_key = get_cookie_key();
 
     server::new(move || {
-        let initial_state = AppState::new();
+        let initial_state = AppState::new(storage.clone());
 
         App::<AppState>::with_state(initial_state)
             .middleware(Logger::default())
@@ -669,9 +1592,29 @@ fn main() {
             .route("/add_pin", http::Method::POST, add_pin)
             .route("/edit/{pin}", http::Method::GET, edit_pin_page)
             .route("/delete/{pin}", http::Method::POST, delete_pin)
+            .route("/trash", http::Method::GET, trash_page)
+            .route("/duplicates", http::Method::GET, duplicates_page)
+            .route("/merge_pins", http::Method::POST, merge_pins)
+            .route("/restore/{pin}", http::Method::POST, restore_pin)
+            .route("/empty_trash", http::Method::POST, empty_trash)
             .route("/edit_pin_data", http::Method::POST, edit_pin_data)
+            .route("/history/{pin}", http::Method::GET, pin_history_page)
+            .route("/view/{pin}", http::Method::GET, view_pin_page)
+            .route("/snapshots/{pin}", http::Method::GET, snapshots_page)
+            .route("/rearchive_tag", http::Method::POST, rearchive_tag)
+            .route(
+                "/restore_revision/{pin}/{revision}",
+                http::Method::POST,
+                restore_revision,
+            )
             .route("/switch_theme", http::Method::POST, switch_theme)
             .route("/user_archive", http::Method::GET, download_archive)
+            .route("/restore", http::Method::GET, restore_page)
+            .route("/restore", http::Method::POST, restore_archive)
+            .route("/import", http::Method::GET, import_page)
+            .route("/import", http::Method::POST, import_bookmarks)
+            .route("/confirm_import", http::Method::POST, confirm_import)
+            .route("/export_bookmarks", http::Method::GET, export_bookmarks)
     })
     .bind("127.0.0.1:8081")
     .unwrap()
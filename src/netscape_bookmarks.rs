@@ -0,0 +1,333 @@
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{
+    BufferQueue, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+
+use crate::htmlrenderer::escape_html;
+use crate::importer::{self, ImportedBookmark};
+use crate::pins::Pin;
+
+const HEADER: &str = "<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+";
+
+/// Text being read, and what it belongs to
+enum Capture {
+    None,
+    Title,
+    FolderName,
+    Description,
+}
+
+/// Reads bookmarks as the file is tokenized. The format is barely html: `<DT>` and `<DD>` are
+/// never closed and folders are a `<H3>` followed by a `<DL>`, which a tree builder would nest
+/// in odd ways.
+struct BookmarkSink {
+    /// Name of the folder of every open `<DL>`, if it's a folder whose name makes a tag
+    folders: Vec<Option<String>>,
+    /// Folder named by the latest `<H3>`, whose `<DL>` comes next
+    next_folder: Option<String>,
+    capture: Capture,
+    text: String,
+    /// Whether a `<DD>` would describe the latest bookmark, rather than a folder
+    after_bookmark: bool,
+    bookmarks: Vec<ImportedBookmark>,
+}
+
+fn attribute(tag: &Tag, name: &str) -> Option<String> {
+    tag.attrs
+        .iter()
+        .find(|a| a.name.local.eq_str_ignore_ascii_case(name))
+        .map(|a| a.value.to_string())
+}
+
+impl BookmarkSink {
+    fn finish_description(&mut self) {
+        if let Capture::Description = self.capture {
+            if let Some(bookmark) = self.bookmarks.last_mut() {
+                bookmark.description = self.text.trim().to_string();
+            }
+            self.capture = Capture::None;
+        }
+    }
+
+    fn start_tag(&mut self, tag: &Tag) {
+        match &*tag.name.to_ascii_lowercase() {
+            "a" => {
+                let mut tags: Vec<String> = self.folders.iter().flatten().cloned().collect();
+                if let Some(tag_list) = attribute(tag, "tags") {
+                    tags.extend(
+                        tag_list
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|t| !t.is_empty())
+                            .map(String::from),
+                    );
+                }
+
+                self.bookmarks.push(ImportedBookmark {
+                    url: attribute(tag, "href").unwrap_or_default(),
+                    tags,
+                    // Pocket writes the date a link was saved in its own attribute
+                    created: attribute(tag, "add_date")
+                        .or_else(|| attribute(tag, "time_added"))
+                        .and_then(|d| importer::parse_date(&d)),
+                    ..Default::default()
+                });
+                self.capture = Capture::Title;
+                self.text.clear();
+            }
+            "h3" => {
+                // The browser's own folders, like the bookmarks toolbar, aren't worth a tag
+                let is_special = attribute(tag, "personal_toolbar_folder").is_some()
+                    || attribute(tag, "unfiled_bookmarks_folder").is_some();
+                self.capture = if is_special {
+                    Capture::None
+                } else {
+                    Capture::FolderName
+                };
+                self.next_folder = None;
+                self.after_bookmark = false;
+                self.text.clear();
+            }
+            "dd" if self.after_bookmark => {
+                self.capture = Capture::Description;
+                self.text.clear();
+            }
+            "dt" => self.finish_description(),
+            "dl" => {
+                self.finish_description();
+                self.folders.push(self.next_folder.take());
+                self.after_bookmark = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn end_tag(&mut self, tag: &Tag) {
+        match &*tag.name.to_ascii_lowercase() {
+            "a" => {
+                if let Some(bookmark) = self.bookmarks.last_mut() {
+                    bookmark.title = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+                }
+                self.capture = Capture::None;
+                self.after_bookmark = true;
+            }
+            "h3" => {
+                if let Capture::FolderName = self.capture {
+                    self.next_folder = Some(self.text.trim().to_string());
+                }
+                self.capture = Capture::None;
+            }
+            "dl" => {
+                self.finish_description();
+                self.folders.pop();
+                self.after_bookmark = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl TokenSink for BookmarkSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) => match tag.kind {
+                TagKind::StartTag => self.start_tag(&tag),
+                TagKind::EndTag => self.end_tag(&tag),
+            },
+            Token::CharacterTokens(text) if !matches!(self.capture, Capture::None) => {
+                self.text.push_str(&text)
+            }
+            Token::EOFToken => self.finish_description(),
+            _ => {}
+        }
+
+        TokenSinkResult::Continue
+    }
+}
+
+/// Reads the bookmarks in a `bookmarks.html` file, as exported by Firefox, Chrome and most
+/// other browsers, or in Pocket's html export. The folders a bookmark is in become its tags,
+/// along with the ones in its `TAGS` attribute.
+pub fn parse_bookmarks(html: &str) -> Vec<ImportedBookmark> {
+    let sink = BookmarkSink {
+        folders: vec![],
+        next_folder: None,
+        capture: Capture::None,
+        text: String::new(),
+        after_bookmark: false,
+        bookmarks: vec![],
+    };
+
+    let mut tokenizer = Tokenizer::new(sink, TokenizerOpts::default());
+    let mut queue = BufferQueue::new();
+    queue.push_back(StrTendril::from(html));
+    let _ = tokenizer.feed(&mut queue);
+    tokenizer.end();
+
+    tokenizer.sink.bookmarks
+}
+
+/// Writes a bookmark for each url of a pin, the first one followed by the pin's description
+fn write_pin(out: &mut String, pin: &Pin, indent: &str) {
+    let add_date = pin.created.timestamp();
+    let last_modified = pin.modified.unwrap_or(pin.created).timestamp();
+    let tags = escape_html(&pin.tags.join(","));
+
+    for (i, url) in pin.urls.iter().filter(|url| !url.is_empty()).enumerate() {
+        let title = if pin.title.trim().is_empty() {
+            url
+        } else {
+            &pin.title
+        };
+
+        out.push_str(&format!(
+            "{}<DT><A HREF=\"{}\" ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\" TAGS=\"{}\">{}</A>\n",
+            indent,
+            escape_html(url),
+            add_date,
+            last_modified,
+            tags,
+            escape_html(title)
+        ));
+
+        if i == 0 && !pin.description.trim().is_empty() {
+            out.push_str(&format!(
+                "{}<DD>{}\n",
+                indent,
+                escape_html(pin.description.trim())
+            ));
+        }
+    }
+}
+
+/// Writes pins in the `bookmarks.html` format that browsers import. Tags go in the `TAGS`
+/// attribute, and with `tag_folders` each pin is also put in a folder named after its first
+/// tag. Pins without an url have no place in the format, so they're left out.
+pub fn write_bookmarks(pins: &[Pin], tag_folders: bool) -> String {
+    let mut out = String::from(HEADER);
+    out.push_str("<DL><p>\n");
+
+    if tag_folders {
+        let mut folders = std::collections::BTreeMap::<&str, Vec<&Pin>>::new();
+        for pin in pins {
+            if let Some(tag) = pin.tags.first() {
+                folders.entry(tag).or_default().push(pin);
+            }
+        }
+
+        for (tag, folder_pins) in folders {
+            out.push_str(&format!(
+                "    <DT><H3>{}</H3>\n    <DL><p>\n",
+                escape_html(tag)
+            ));
+            for pin in folder_pins {
+                write_pin(&mut out, pin, "        ");
+            }
+            out.push_str("    </DL><p>\n");
+        }
+    }
+
+    for pin in pins {
+        if !tag_folders || pin.tags.is_empty() {
+            write_pin(&mut out, pin, "    ");
+        }
+    }
+
+    out.push_str("</DL>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn pins() -> Vec<Pin> {
+        let mut first = Pin::new();
+        first.id = "first".into();
+        first.title = "Tom & Jerry <3 \"quotes\"".into();
+        first.urls = vec![
+            "https://example.com/?a=1&b=2".into(),
+            "https://mirror.example.org/".into(),
+        ];
+        first.tags = vec!["cartoons".into(), "c++".into()];
+        first.description = "Cats & mice\nsecond line".into();
+        first.created = Utc.with_ymd_and_hms(2015, 3, 14, 9, 26, 53).unwrap();
+
+        let mut second = Pin::new();
+        second.id = "second".into();
+        second.urls = vec!["https://example.net/untitled".into()];
+        second.created = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+
+        vec![first, second]
+    }
+
+    #[test]
+    fn written_bookmarks_read_back() {
+        let bookmarks = parse_bookmarks(&write_bookmarks(&pins(), false));
+
+        let read: Vec<_> = bookmarks
+            .iter()
+            .map(|b| {
+                (
+                    b.url.as_str(),
+                    b.title.as_str(),
+                    b.tags.clone(),
+                    b.description.as_str(),
+                    b.created.map(|c| c.timestamp()),
+                )
+            })
+            .collect();
+        let tags = vec!["cartoons".to_string(), "c++".to_string()];
+        assert_eq!(
+            read,
+            vec![
+                (
+                    "https://example.com/?a=1&b=2",
+                    "Tom & Jerry <3 \"quotes\"",
+                    tags.clone(),
+                    "Cats & mice\nsecond line",
+                    Some(1426325213)
+                ),
+                (
+                    "https://mirror.example.org/",
+                    "Tom & Jerry <3 \"quotes\"",
+                    tags,
+                    "",
+                    Some(1426325213)
+                ),
+                (
+                    "https://example.net/untitled",
+                    "https://example.net/untitled",
+                    vec![],
+                    "",
+                    Some(1577836800)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn tag_folders_read_back_as_tags() {
+        let bookmarks = parse_bookmarks(&write_bookmarks(&pins(), true));
+        assert_eq!(bookmarks.len(), 3);
+
+        for bookmark in &bookmarks[..2] {
+            let mut tags = bookmark.tags.clone();
+            tags.sort();
+            tags.dedup();
+            assert_eq!(tags, vec!["c++", "cartoons"]);
+        }
+        assert_eq!(bookmarks[0].description, "Cats & mice\nsecond line");
+        assert!(bookmarks[2].tags.is_empty());
+    }
+}
@@ -0,0 +1,522 @@
+use base64::prelude::*;
+use failure::Error;
+use html5ever::rcdom::{Handle, NodeData};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::http_client::HttpClient;
+use crate::url_resolver::{self, URL_ATTRIBUTES};
+
+/// Stylesheets importing stylesheets importing stylesheets stop being followed at some point
+const MAX_CSS_DEPTH: u32 = 4;
+
+/// How much gets downloaded to inline the assets of a page. Assets that don't fit keep pointing
+/// to the live site.
+pub struct InlineLimits {
+    pub max_asset_size: usize,
+    pub max_total_size: usize,
+    /// Time spent downloading the assets of a single page
+    pub max_time: Duration,
+}
+
+/// A single candidate of a `srcset` attribute, like `image.png 2x`
+pub struct SrcsetCandidate {
+    pub url: String,
+    pub descriptor: String,
+}
+
+/// Splits a `srcset` attribute into its candidates. Urls can contain commas, so this follows
+/// the html spec rather than splitting on them.
+pub fn parse_srcset(srcset: &str) -> Vec<SrcsetCandidate> {
+    let mut candidates = vec![];
+    let mut rest = srcset;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+
+        let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let url = &rest[..url_end];
+        rest = &rest[url_end..];
+
+        // A url ending in a comma has no descriptor
+        if url.ends_with(',') {
+            candidates.push(SrcsetCandidate {
+                url: url.trim_end_matches(',').to_string(),
+                descriptor: String::new(),
+            });
+            continue;
+        }
+
+        let descriptor_end = rest.find(',').unwrap_or(rest.len());
+        candidates.push(SrcsetCandidate {
+            url: url.to_string(),
+            descriptor: rest[..descriptor_end].trim().to_string(),
+        });
+        rest = &rest[descriptor_end..];
+    }
+
+    candidates
+}
+
+pub fn format_srcset(candidates: &[SrcsetCandidate]) -> String {
+    candidates
+        .iter()
+        .map(|c| {
+            if c.descriptor.is_empty() {
+                c.url.clone()
+            } else {
+                format!("{} {}", c.url, c.descriptor)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Replaces every url referenced by a stylesheet, in `url()` or `@import "..."`, with what
+/// `replace` returns for it. Urls it returns `None` for are left alone.
+pub fn replace_css_urls<F>(css: &str, mut replace: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    // Lowercasing ascii keeps byte offsets the same, so they can be used on `css` directly
+    let lowercase = css.to_ascii_lowercase();
+    let mut result = String::with_capacity(css.len());
+    let mut position = 0;
+
+    loop {
+        let next_url = lowercase[position..].find("url(").map(|i| position + i);
+        let next_import = lowercase[position..].find("@import").map(|i| position + i);
+
+        let start = match (next_url, next_import) {
+            (None, None) => break,
+            (Some(url), Some(import)) => std::cmp::min(url, import),
+            (Some(x), None) | (None, Some(x)) => x,
+        };
+
+        if Some(start) == next_import {
+            let after_import = start + "@import".len();
+            let value_start =
+                after_import + (css[after_import..].len() - css[after_import..].trim_start().len());
+            let quote = css[value_start..].chars().next();
+
+            // `@import url(...)` is taken care of as any other url()
+            let quote = match quote {
+                Some(q) if q == '"' || q == '\'' => q,
+                _ => {
+                    result.push_str(&css[position..after_import]);
+                    position = after_import;
+                    continue;
+                }
+            };
+
+            let value_end = match css[value_start + 1..].find(quote) {
+                None => break,
+                Some(i) => value_start + 1 + i,
+            };
+
+            result.push_str(&css[position..start]);
+            match replace(&css[value_start + 1..value_end]) {
+                Some(new_url) => result.push_str(&format!("@import url(\"{}\")", new_url)),
+                None => result.push_str(&css[start..=value_end]),
+            }
+            position = value_end + 1;
+        } else {
+            let value_start = start + "url(".len();
+            let value_end = match css[value_start..].find(')') {
+                None => break,
+                Some(i) => value_start + i,
+            };
+
+            let value = css[value_start..value_end]
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'');
+
+            result.push_str(&css[position..start]);
+            match replace(value) {
+                Some(new_url) => result.push_str(&format!("url(\"{}\")", new_url)),
+                None => result.push_str(&css[start..=value_end]),
+            }
+            position = value_end + 1;
+        }
+    }
+
+    result.push_str(&css[position..]);
+    result
+}
+
+/// Mime type for assets served without a Content-Type
+fn guess_mime_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_ref() {
+        "css" => "text/css",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
+
+struct Inliner<'a> {
+    client: &'a dyn HttpClient,
+    limits: &'a InlineLimits,
+    /// Bytes downloaded so far
+    total_size: usize,
+    /// Assets aren't downloaded anymore after this
+    deadline: Instant,
+    /// Data URIs already built, as pages tend to reference the same asset many times
+    inlined: HashMap<String, Option<String>>,
+}
+
+impl<'a> Inliner<'a> {
+    /// Returns a data URI with the contents of `reference`, resolved against `base`
+    fn data_uri(&mut self, base: &Url, reference: &str, css_depth: u32) -> Option<String> {
+        let reference = reference.trim();
+        if reference.is_empty() || reference.starts_with("data:") || reference.starts_with('#') {
+            return None;
+        }
+
+        let url = base.join(reference).ok()?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return None;
+        }
+
+        if let Some(data_uri) = self.inlined.get(url.as_str()) {
+            return data_uri.clone();
+        }
+
+        let data_uri = self.fetch_data_uri(&url, css_depth);
+        self.inlined.insert(url.to_string(), data_uri.clone());
+        data_uri
+    }
+
+    fn fetch_data_uri(&mut self, url: &Url, css_depth: u32) -> Option<String> {
+        let max_size = std::cmp::min(
+            self.limits.max_asset_size,
+            self.limits.max_total_size.saturating_sub(self.total_size),
+        );
+        if max_size == 0 || Instant::now() >= self.deadline {
+            return None;
+        }
+
+        let response = match self.client.get(url.as_str(), max_size) {
+            Err(err) => {
+                info!("Not inlining {}: {}", url, err);
+                return None;
+            }
+            Ok(x) => x,
+        };
+
+        if !(200..300).contains(&response.status) {
+            info!("Not inlining {}: got status {}", url, response.status);
+            return None;
+        }
+
+        self.total_size += response.body.len();
+
+        let mime_type = response
+            .content_type
+            .as_ref()
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().to_lowercase())
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| guess_mime_type(url.path()).to_string());
+
+        let body = if mime_type == "text/css" && css_depth < MAX_CSS_DEPTH {
+            // Urls in a stylesheet are relative to the stylesheet, not to the page
+            let css_url = Url::parse(&response.url).unwrap_or_else(|_| url.clone());
+            self.inline_css(
+                &String::from_utf8_lossy(&response.body),
+                &css_url,
+                css_depth + 1,
+            )
+            .into_bytes()
+        } else {
+            response.body
+        };
+
+        Some(format!(
+            "data:{};base64,{}",
+            mime_type,
+            BASE64_STANDARD.encode(&body)
+        ))
+    }
+
+    fn inline_css(&mut self, css: &str, base: &Url, css_depth: u32) -> String {
+        replace_css_urls(css, |reference| self.data_uri(base, reference, css_depth))
+    }
+
+    fn inline_attribute(&mut self, node: &Handle, attribute: &str, base: &Url) {
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            for attr in attrs.borrow_mut().iter_mut() {
+                if attr.name.local.eq_str_ignore_ascii_case(attribute) {
+                    if let Some(data_uri) = self.data_uri(base, &attr.value, 0) {
+                        attr.value = data_uri.into();
+                    }
+                }
+            }
+        }
+    }
+
+    fn inline_srcset(&mut self, node: &Handle, base: &Url) {
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            for attr in attrs.borrow_mut().iter_mut() {
+                if attr.name.local.eq_str_ignore_ascii_case("srcset") {
+                    let mut candidates = parse_srcset(&attr.value);
+                    for candidate in candidates.iter_mut() {
+                        if let Some(data_uri) = self.data_uri(base, &candidate.url, 0) {
+                            candidate.url = data_uri;
+                        }
+                    }
+                    attr.value = format_srcset(&candidates).into();
+                }
+            }
+        }
+    }
+
+    fn inline_node(&mut self, node: &Handle, base: &Url) {
+        if let NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } = node.data
+        {
+            // Scripts won't run from the cache anyway, so event handlers and javascript: links
+            // go away along with them
+            attrs.borrow_mut().retain(|attr| {
+                let is_event_handler = attr.name.local.starts_with("on");
+                let is_script_link = URL_ATTRIBUTES
+                    .contains(&url_resolver::attribute_name(attr).as_str())
+                    && attr
+                        .value
+                        .trim_start()
+                        .to_lowercase()
+                        .starts_with("javascript:");
+                !is_event_handler && !is_script_link
+            });
+
+            for attr in attrs.borrow_mut().iter_mut() {
+                if attr.name.local.eq_str_ignore_ascii_case("style") {
+                    attr.value = self.inline_css(&attr.value, base, 0).into();
+                }
+            }
+
+            match &*name.local.to_ascii_lowercase() {
+                "img" => {
+                    self.inline_attribute(node, "src", base);
+                    self.inline_srcset(node, base);
+                }
+                "source" => self.inline_srcset(node, base),
+                "input" => self.inline_attribute(node, "src", base),
+                "video" => self.inline_attribute(node, "poster", base),
+                "link" => {
+                    let rel = attrs
+                        .borrow()
+                        .iter()
+                        .find(|a| a.name.local.eq_str_ignore_ascii_case("rel"))
+                        .map(|a| a.value.to_lowercase())
+                        .unwrap_or_default();
+                    let is_inlined = rel
+                        .split_whitespace()
+                        .any(|r| r == "stylesheet" || r == "icon" || r == "apple-touch-icon");
+                    if is_inlined {
+                        self.inline_attribute(node, "href", base);
+                    }
+                }
+                "style" => {
+                    for child in node.children.borrow().iter() {
+                        if let NodeData::Text { ref contents } = child.data {
+                            let css = self.inline_css(&contents.borrow(), base, 0);
+                            *contents.borrow_mut() = css.into();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        node.children.borrow_mut().retain(|child| match child.data {
+            NodeData::Element { ref name, .. } => !name.local.eq_str_ignore_ascii_case("script"),
+            _ => true,
+        });
+
+        for child in node.children.borrow().iter() {
+            self.inline_node(child, base);
+        }
+    }
+}
+
+/// Makes a page self-contained, so it still works once the original site is gone: stylesheets,
+/// images and fonts are downloaded and embedded as data URIs, and scripts are removed.
+pub fn inline_assets(
+    document: &Handle,
+    page_url: &str,
+    client: &dyn HttpClient,
+    limits: &InlineLimits,
+) -> Result<(), Error> {
+    let base = Url::parse(page_url)?;
+
+    let mut inliner = Inliner {
+        client,
+        limits,
+        total_size: 0,
+        deadline: Instant::now() + limits.max_time,
+        inlined: HashMap::new(),
+    };
+    inliner.inline_node(document, &base);
+
+    if Instant::now() >= inliner.deadline {
+        info!(
+            "Ran out of time inlining assets into {}, the rest point to the live site",
+            page_url
+        );
+    }
+
+    if inliner.total_size > 0 {
+        info!(
+            "Inlined {} bytes of assets into {}",
+            inliner.total_size, page_url
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, FakeHttpClient};
+    use html5ever::tendril::TendrilSink;
+
+    fn srcset_round_trip(srcset: &str) -> Vec<(String, String)> {
+        parse_srcset(srcset)
+            .into_iter()
+            .map(|c| (c.url, c.descriptor))
+            .collect()
+    }
+
+    fn pair(url: &str, descriptor: &str) -> (String, String) {
+        (url.to_string(), descriptor.to_string())
+    }
+
+    #[test]
+    fn srcset_candidates_are_split() {
+        assert_eq!(
+            srcset_round_trip("small.png 1x,  big.png 2x"),
+            vec![pair("small.png", "1x"), pair("big.png", "2x")]
+        );
+        assert_eq!(
+            srcset_round_trip("a.png, b.png 800w"),
+            vec![pair("a.png", ""), pair("b.png", "800w")]
+        );
+        assert_eq!(srcset_round_trip(" , "), vec![]);
+    }
+
+    #[test]
+    fn srcset_urls_can_contain_commas() {
+        assert_eq!(
+            srcset_round_trip("data:image/png;base64,AAAA 1x, /img,v2.png 2x"),
+            vec![
+                pair("data:image/png;base64,AAAA", "1x"),
+                pair("/img,v2.png", "2x")
+            ]
+        );
+    }
+
+    #[test]
+    fn srcset_is_formatted_back() {
+        let candidates = parse_srcset("a.png,  b.png   2x");
+        assert_eq!(format_srcset(&candidates), "a.png, b.png 2x");
+    }
+
+    fn upper(css: &str) -> String {
+        replace_css_urls(css, |url| Some(url.to_uppercase()))
+    }
+
+    #[test]
+    fn css_urls_are_replaced() {
+        assert_eq!(
+            upper("a { background: url(a.png) } b { background: URL( 'b.png' ) }"),
+            "a { background: url(\"A.PNG\") } b { background: url(\"B.PNG\") }"
+        );
+        assert_eq!(upper("@import 'x.css';"), "@import url(\"X.CSS\");");
+        assert_eq!(
+            upper("@import url(\"x.css\") screen;"),
+            "@import url(\"X.CSS\") screen;"
+        );
+    }
+
+    #[test]
+    fn css_urls_are_kept_when_not_replaced() {
+        let css = "é { background: url(keep.png) } @import \"keep.css\"; i { x: url(y.png) }";
+        assert_eq!(
+            replace_css_urls(css, |url| if url == "y.png" {
+                Some("z.png".into())
+            } else {
+                None
+            }),
+            "é { background: url(keep.png) } @import \"keep.css\"; i { x: url(\"z.png\") }"
+        );
+    }
+
+    #[test]
+    fn unterminated_css_urls_are_left_alone() {
+        assert_eq!(
+            upper("a { background: url(a.png"),
+            "a { background: url(a.png"
+        );
+    }
+
+    fn inline(html: &str, client: &FakeHttpClient, max_time: Duration) -> String {
+        let dom = html5ever::parse_document(html5ever::rcdom::RcDom::default(), Default::default())
+            .one(html);
+        let limits = InlineLimits {
+            max_asset_size: 1024,
+            max_total_size: 4096,
+            max_time,
+        };
+        inline_assets(&dom.document, "http://example.com/", client, &limits).unwrap();
+
+        let mut out = vec![];
+        html5ever::serialize(&mut out, &dom.document, Default::default()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn only_script_urls_are_removed() {
+        let page = inline(
+            r#"<a href="javascript:void(0)" title="javascript: the good parts" onclick="x()">a</a>"#,
+            &FakeHttpClient::new(),
+            Duration::from_secs(60),
+        );
+        assert!(!page.contains("href"));
+        assert!(!page.contains("onclick"));
+        assert!(page.contains(r#"title="javascript: the good parts""#));
+    }
+
+    #[test]
+    fn images_are_inlined_until_the_deadline() {
+        let client = FakeHttpClient::new();
+        client.respond(
+            "GET",
+            "http://example.com/a.png",
+            response("http://example.com/a.png", 200, "image/png", b"png"),
+        );
+
+        let page = inline(r#"<img src="a.png">"#, &client, Duration::from_secs(60));
+        assert!(page.contains(r#"src="data:image/png;base64,cG5n""#));
+
+        let page = inline(r#"<img src="a.png">"#, &client, Duration::from_secs(0));
+        assert!(page.contains(r#"src="a.png""#));
+    }
+}
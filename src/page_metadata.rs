@@ -0,0 +1,123 @@
+use html5ever::rcdom::{Handle, NodeData};
+
+/// What a page says about itself, used to fill in pins added with only a url
+#[derive(Debug, Default)]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Url of the page's icon, if it declares one
+    pub favicon_url: Option<String>,
+    /// Url the page says it's best known by, from its `<link rel=canonical>`
+    pub canonical_url: Option<String>,
+}
+
+/// Metadata found while walking the page, before deciding which of it to use
+#[derive(Default)]
+struct Candidates {
+    title: Option<String>,
+    og_title: Option<String>,
+    description: Option<String>,
+    og_description: Option<String>,
+    icon: Option<String>,
+    touch_icon: Option<String>,
+    canonical: Option<String>,
+}
+
+fn attribute(node: &Handle, name: &str) -> Option<String> {
+    match node.data {
+        NodeData::Element { ref attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|a| a.name.local.eq_str_ignore_ascii_case(name))
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+fn text_content(node: &Handle, text: &mut String) {
+    if let NodeData::Text { ref contents } = node.data {
+        text.push_str(&contents.borrow());
+    }
+
+    for child in node.children.borrow().iter() {
+        text_content(child, text);
+    }
+}
+
+/// Collapses runs of whitespace, returning `None` if nothing is left
+fn clean_text(text: &str) -> Option<String> {
+    let cleaned = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
+fn collect_candidates(node: &Handle, candidates: &mut Candidates) {
+    if let NodeData::Element { ref name, .. } = node.data {
+        match &*name.local.to_ascii_lowercase() {
+            "title" if candidates.title.is_none() => {
+                let mut text = String::new();
+                text_content(node, &mut text);
+                candidates.title = clean_text(&text);
+            }
+            "meta" => {
+                // Open Graph tags use `property`, but some sites put them in `name`
+                let key = attribute(node, "property")
+                    .or_else(|| attribute(node, "name"))
+                    .unwrap_or_default()
+                    .to_lowercase();
+                let content = attribute(node, "content").and_then(|c| clean_text(&c));
+
+                let slot = match key.as_ref() {
+                    "description" => Some(&mut candidates.description),
+                    "og:title" => Some(&mut candidates.og_title),
+                    "og:description" => Some(&mut candidates.og_description),
+                    _ => None,
+                };
+                if let Some(slot) = slot {
+                    if slot.is_none() {
+                        *slot = content;
+                    }
+                }
+            }
+            "link" => {
+                let rel = attribute(node, "rel").unwrap_or_default().to_lowercase();
+                let href = attribute(node, "href").filter(|h| !h.trim().is_empty());
+                // Svg icons can't be turned into png, so they're skipped
+                let is_svg = attribute(node, "type").unwrap_or_default() == "image/svg+xml"
+                    || href
+                        .as_ref()
+                        .is_some_and(|h| h.to_lowercase().ends_with(".svg"));
+
+                if rel.split_whitespace().any(|r| r == "icon") && !is_svg {
+                    candidates.icon = candidates.icon.take().or(href);
+                } else if rel.split_whitespace().any(|r| r == "apple-touch-icon") {
+                    candidates.touch_icon = candidates.touch_icon.take().or(href);
+                } else if rel.split_whitespace().any(|r| r == "canonical") {
+                    candidates.canonical = candidates.canonical.take().or(href);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for child in node.children.borrow().iter() {
+        collect_candidates(child, candidates);
+    }
+}
+
+/// Finds a page's title, description, icon and canonical url. Urls in the page should already be
+/// absolute.
+pub fn extract_metadata(document: &Handle) -> PageMetadata {
+    let mut candidates = Candidates::default();
+    collect_candidates(document, &mut candidates);
+
+    PageMetadata {
+        title: candidates.title.or(candidates.og_title),
+        description: candidates.description.or(candidates.og_description),
+        favicon_url: candidates.icon.or(candidates.touch_icon),
+        canonical_url: candidates.canonical,
+    }
+}
@@ -0,0 +1,339 @@
+use failure::Error;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A page as the browser left it
+pub struct DumpedPage {
+    pub html: Vec<u8>,
+    /// Where the browser ended up after any redirects, for browsers that tell
+    pub url: Option<String>,
+}
+
+/// Something able to load a web page, usually a headless browser
+pub trait PageRenderer: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Saves a png screenshot of the page at `url`, taken with a `width` x `height` window
+    fn screenshot(
+        &self,
+        url: &str,
+        width: u32,
+        height: u32,
+        output: &Path,
+        timeout: Duration,
+    ) -> Result<(), Error>;
+
+    /// Returns the html of the page at `url` once it's loaded and its scripts have run
+    fn dump_dom(&self, url: &str, timeout: Duration) -> Result<DumpedPage, Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RendererChoice {
+    /// Firefox for screenshots and Chromium for pages, or whichever of them is installed
+    Auto,
+    Firefox,
+    Chromium,
+    /// Serves fixture files instead of running a browser
+    Fake,
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut contents = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut contents).unwrap_or(0);
+        }
+        contents
+    })
+}
+
+/// Runs a browser command, killing it if it hasn't finished after `timeout`. Browsers have a
+/// habit of hanging on some pages, which would otherwise block a worker forever.
+fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<Output, Error> {
+    use std::os::unix::process::CommandExt;
+
+    // Browsers start helper processes of their own, so the child gets its own process group
+    // that can be killed as a whole
+    let mut child = command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Pipes are drained on their own threads, so a chatty child can't block on a full pipe
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            child.wait()?;
+            bail!("Browser timed out after {} seconds", timeout.as_secs());
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Runs a browser with `run_with_timeout`, logging whatever it complains about
+fn run_browser(name: &str, command: &mut Command, timeout: Duration) -> Result<Output, Error> {
+    let output = run_with_timeout(command, timeout).map_err(|e| {
+        error!("Could not execute {}: {}", name, e);
+        e
+    })?;
+
+    if !output.stderr.is_empty() {
+        error!(
+            "Errors from {}: {}",
+            name,
+            std::str::from_utf8(&output.stderr).unwrap_or("COULD NOT READ STDERR")
+        );
+    }
+
+    Ok(output)
+}
+
+/// Returns the first of `candidates` that exists
+fn find_binary(candidates: &[&str]) -> Option<PathBuf> {
+    candidates
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+}
+
+pub struct FirefoxRenderer {
+    binary: PathBuf,
+}
+
+impl FirefoxRenderer {
+    pub fn find() -> Option<FirefoxRenderer> {
+        find_binary(&["/usr/bin/firefox", "/usr/local/bin/firefox"])
+            .map(|binary| FirefoxRenderer { binary })
+    }
+}
+
+impl PageRenderer for FirefoxRenderer {
+    fn name(&self) -> &str {
+        "firefox"
+    }
+
+    fn screenshot(
+        &self,
+        url: &str,
+        width: u32,
+        height: u32,
+        output: &Path,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        run_browser(
+            self.name(),
+            Command::new(&self.binary)
+                .arg("--headless")
+                .arg(format!("--window-size={},{}", width, height))
+                .arg("--screenshot")
+                .arg(output)
+                .arg(url),
+            timeout,
+        )?;
+
+        Ok(())
+    }
+
+    fn dump_dom(&self, _url: &str, _timeout: Duration) -> Result<DumpedPage, Error> {
+        bail!("Firefox can't dump the DOM of a page from the command line")
+    }
+}
+
+pub struct ChromiumRenderer {
+    binary: PathBuf,
+}
+
+impl ChromiumRenderer {
+    pub fn find() -> Option<ChromiumRenderer> {
+        find_binary(&[
+            "/usr/bin/google-chrome",
+            "/usr/bin/chromium",
+            "/usr/bin/chromium-browser",
+        ])
+        .map(|binary| ChromiumRenderer { binary })
+    }
+}
+
+impl PageRenderer for ChromiumRenderer {
+    fn name(&self) -> &str {
+        "chromium"
+    }
+
+    fn screenshot(
+        &self,
+        url: &str,
+        width: u32,
+        height: u32,
+        output: &Path,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let mut screenshot_arg = std::ffi::OsString::from("--screenshot=");
+        screenshot_arg.push(output);
+
+        run_browser(
+            self.name(),
+            Command::new(&self.binary)
+                .arg("--headless")
+                .arg("--disable-gpu")
+                .arg(format!("--window-size={},{}", width, height))
+                .arg(screenshot_arg)
+                .arg(url),
+            timeout,
+        )?;
+
+        Ok(())
+    }
+
+    fn dump_dom(&self, url: &str, timeout: Duration) -> Result<DumpedPage, Error> {
+        let output = run_browser(
+            self.name(),
+            Command::new(&self.binary)
+                .arg("--headless")
+                .arg("--disable-gpu")
+                .arg("--dump-dom")
+                .arg(url),
+            timeout,
+        )?;
+
+        // --dump-dom only prints the page, not where it was loaded from
+        Ok(DumpedPage {
+            html: output.stdout,
+            url: None,
+        })
+    }
+}
+
+/// Serves files from a fixtures directory instead of loading anything, so downloads behave the
+/// same everywhere. A page for host `example.com` is `example.com.html`, falling back to
+/// `page.html`, and likewise screenshots are `example.com.png` or `screenshot.png`.
+pub struct FakeRenderer {
+    fixtures: PathBuf,
+}
+
+impl FakeRenderer {
+    pub fn new<P: AsRef<Path>>(fixtures: P) -> FakeRenderer {
+        FakeRenderer {
+            fixtures: fixtures.as_ref().to_path_buf(),
+        }
+    }
+
+    fn fixture(&self, url: &str, extension: &str, fallback: &str) -> Result<PathBuf, Error> {
+        let host = url
+            .parse::<actix_web::http::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(String::from))
+            .unwrap_or_default();
+
+        let for_host = self.fixtures.join(format!("{}.{}", host, extension));
+        if !host.is_empty() && for_host.exists() {
+            return Ok(for_host);
+        }
+
+        let fallback = self.fixtures.join(fallback);
+        ensure!(fallback.exists(), "No fixture found for {}", url);
+        Ok(fallback)
+    }
+}
+
+impl PageRenderer for FakeRenderer {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    fn screenshot(
+        &self,
+        url: &str,
+        _width: u32,
+        _height: u32,
+        output: &Path,
+        _timeout: Duration,
+    ) -> Result<(), Error> {
+        std::fs::copy(self.fixture(url, "png", "screenshot.png")?, output)?;
+        Ok(())
+    }
+
+    /// Fixtures are served as they are, as if `url` never redirected
+    fn dump_dom(&self, url: &str, _timeout: Duration) -> Result<DumpedPage, Error> {
+        Ok(DumpedPage {
+            html: std::fs::read(self.fixture(url, "html", "page.html")?)?,
+            url: Some(url.to_string()),
+        })
+    }
+}
+
+/// Renderers used for each step of downloading a page. Either of them is missing when no
+/// suitable browser is installed, in which case that step is skipped.
+#[derive(Clone)]
+pub struct Renderers {
+    pub screenshots: Option<Arc<dyn PageRenderer>>,
+    pub pages: Option<Arc<dyn PageRenderer>>,
+}
+
+impl Renderers {
+    pub fn new(choice: RendererChoice, fixtures: &str) -> Renderers {
+        let firefox = || FirefoxRenderer::find().map(|r| Arc::new(r) as Arc<dyn PageRenderer>);
+        let chromium = || ChromiumRenderer::find().map(|r| Arc::new(r) as Arc<dyn PageRenderer>);
+
+        let renderers = match choice {
+            RendererChoice::Auto => Renderers {
+                // Chromium tends to hang while taking screenshots, so Firefox goes first
+                screenshots: firefox().or_else(chromium),
+                pages: chromium(),
+            },
+            RendererChoice::Firefox => Renderers {
+                screenshots: firefox(),
+                pages: None,
+            },
+            RendererChoice::Chromium => Renderers {
+                screenshots: chromium(),
+                pages: chromium(),
+            },
+            RendererChoice::Fake => {
+                let fake: Arc<dyn PageRenderer> = Arc::new(FakeRenderer::new(fixtures));
+                Renderers {
+                    screenshots: Some(fake.clone()),
+                    pages: Some(fake),
+                }
+            }
+        };
+
+        match &renderers.screenshots {
+            None => warn!("No browser found to take screenshots with, they will be skipped"),
+            Some(r) => info!("Taking screenshots with {}", r.name()),
+        }
+        match &renderers.pages {
+            None => warn!("No browser found to cache pages with, they will be skipped"),
+            Some(r) => info!("Caching pages with {}", r.name()),
+        }
+
+        renderers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.screenshots.is_none() && self.pages.is_none()
+    }
+}
@@ -0,0 +1,113 @@
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::pins::Pin;
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineChange {
+    Added,
+    Removed,
+    Unchanged,
+}
+
+#[derive(Serialize)]
+pub struct DiffLine {
+    pub change: LineChange,
+    pub text: String,
+}
+
+#[derive(Serialize)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub lines: Vec<DiffLine>,
+}
+
+#[derive(Serialize)]
+pub struct Revision {
+    pub revision: u32,
+    pub date: DateTime<Utc>,
+    pub pin: Pin,
+    /// Changes from the previous revision. Empty for the first one.
+    pub changes: Vec<FieldDiff>,
+}
+
+/// Line by line diff of two texts
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    diff::lines(old, new)
+        .into_iter()
+        .map(|line| match line {
+            diff::Result::Left(x) => DiffLine {
+                change: LineChange::Removed,
+                text: x.to_string(),
+            },
+            diff::Result::Right(x) => DiffLine {
+                change: LineChange::Added,
+                text: x.to_string(),
+            },
+            diff::Result::Both(x, _) => DiffLine {
+                change: LineChange::Unchanged,
+                text: x.to_string(),
+            },
+        })
+        .collect()
+}
+
+fn diff_field(field: &'static str, old: &str, new: &str) -> Option<FieldDiff> {
+    if old == new {
+        return None;
+    }
+
+    Some(FieldDiff {
+        field,
+        lines: diff_lines(old, new),
+    })
+}
+
+fn format_days(days: Option<u32>) -> String {
+    match days {
+        None => String::new(),
+        Some(days) => format!("{} days", days),
+    }
+}
+
+fn diff_pins(old: &Pin, new: &Pin) -> Vec<FieldDiff> {
+    vec![
+        diff_field("Title", &old.title, &new.title),
+        diff_field("Urls", &old.urls.join("\n"), &new.urls.join("\n")),
+        diff_field("Tags", &old.tags.join("\n"), &new.tags.join("\n")),
+        diff_field("Description", &old.description, &new.description),
+        diff_field(
+            "Re-archive every",
+            &format_days(old.rearchive_days),
+            &format_days(new.rearchive_days),
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Turns the revisions of a pin, oldest first and along with their numbers, into a history with
+/// the changes introduced by each revision. The history is returned newest first, which is how it
+/// gets displayed.
+pub fn build_history(revisions: Vec<(u32, Pin)>) -> Vec<Revision> {
+    let mut history: Vec<Revision> = vec![];
+
+    for (revision, pin) in revisions {
+        let changes = match history.last() {
+            None => vec![],
+            Some(previous) => diff_pins(&previous.pin, &pin),
+        };
+
+        history.push(Revision {
+            revision,
+            date: pin.modified.unwrap_or(pin.created),
+            pin,
+            changes,
+        });
+    }
+
+    history.reverse();
+    history
+}
@@ -0,0 +1,178 @@
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::pins::{count_tags, Pin};
+
+/// Everything we keep in memory for a single user, so rendering a page doesn't have to go to disk
+pub struct UserIndex {
+    /// All pins for the user, newest first
+    pub pins: Vec<Pin>,
+    pub tags: Vec<(String, usize)>,
+}
+
+impl UserIndex {
+    fn new(pins: Vec<Pin>) -> UserIndex {
+        let tags = count_tags(&pins);
+        UserIndex { pins, tags }
+    }
+}
+
+/// Per-user cache of pins and tag counts. Users are loaded lazily the first time they're needed,
+/// and dropped from the cache whenever their data changes on disk.
+#[derive(Clone, Default)]
+pub struct PinIndex {
+    users: Arc<RwLock<HashMap<String, Arc<UserIndex>>>>,
+    /// Held while a user is being loaded, and by every change to a user, so a change made while
+    /// the user's pins are being read isn't lost when the load finishes
+    user_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl PinIndex {
+    pub fn new() -> PinIndex {
+        PinIndex::default()
+    }
+
+    fn user_lock(&self, username: &str) -> Arc<Mutex<()>> {
+        self.user_locks
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn get_or_load<F>(&self, username: &str, loader: F) -> Result<Arc<UserIndex>, Error>
+    where
+        F: FnOnce() -> Result<Vec<Pin>, Error>,
+    {
+        if let Some(user_index) = self.users.read().unwrap().get(username) {
+            return Ok(user_index.clone());
+        }
+
+        let user_lock = self.user_lock(username);
+        let _guard = user_lock.lock().unwrap();
+
+        // Someone else may have loaded the user while we waited for the lock
+        if let Some(user_index) = self.users.read().unwrap().get(username) {
+            return Ok(user_index.clone());
+        }
+
+        let user_index = Arc::new(UserIndex::new(loader()?));
+        Ok(self
+            .users
+            .write()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert(user_index)
+            .clone())
+    }
+
+    /// Inserts or replaces a pin for an already loaded user. If the user isn't loaded, there's
+    /// nothing to do, as the pin will be read along with everything else on the next load.
+    pub fn update_pin(&self, username: &str, pin: &Pin) {
+        self.update_pins(username, std::slice::from_ref(pin));
+    }
+
+    /// Same as `update_pin` for many pins at once, which only rebuilds the user's index once
+    pub fn update_pins(&self, username: &str, updated: &[Pin]) {
+        self.modify_user(username, |pins| {
+            let ids: HashSet<&str> = updated.iter().map(|p| p.id.as_str()).collect();
+            pins.retain(|p| !ids.contains(p.id.as_str()));
+            pins.extend(updated.iter().cloned());
+            pins.sort_by_key(|p| std::cmp::Reverse(p.created));
+        });
+    }
+
+    pub fn remove_pin(&self, username: &str, id: &str) {
+        self.modify_user(username, |pins| pins.retain(|p| p.id != id));
+    }
+
+    pub fn invalidate(&self, username: &str) {
+        let user_lock = self.user_lock(username);
+        let _guard = user_lock.lock().unwrap();
+
+        if self.users.write().unwrap().remove(username).is_some() {
+            debug!("Invalidated pin index for user {}", username);
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        // Every user that was ever loaded has a lock
+        let usernames: Vec<String> = self.user_locks.lock().unwrap().keys().cloned().collect();
+        for username in usernames {
+            self.invalidate(&username);
+        }
+    }
+
+    fn modify_user<F>(&self, username: &str, f: F)
+    where
+        F: FnOnce(&mut Vec<Pin>),
+    {
+        let user_lock = self.user_lock(username);
+        let _guard = user_lock.lock().unwrap();
+
+        let mut users = self.users.write().unwrap();
+        if let Some(user_index) = users.get_mut(username) {
+            let mut pins = user_index.pins.clone();
+            f(&mut pins);
+            *user_index = Arc::new(UserIndex::new(pins));
+        }
+    }
+
+    /// Watches the directory pins are stored in, invalidating users whenever their files are
+    /// touched outside of recense, e.g. when restoring a backup by hand. Our own writes also get
+    /// reported, which just means the user is reloaded once more than strictly necessary.
+    pub fn watch_directory(&self, directory: &Path) -> Result<(), Error> {
+        use notify::{DebouncedEvent, Watcher};
+
+        std::fs::create_dir_all(directory)?;
+        let root = directory.canonicalize()?;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::watcher(tx, Duration::from_secs(1))?;
+        watcher.watch(&root, notify::RecursiveMode::Recursive)?;
+
+        let index = self.clone();
+        std::thread::spawn(move || {
+            // The watcher stops as soon as it's dropped, so it has to live in this thread
+            let _watcher = watcher;
+
+            for event in rx {
+                let paths: Vec<PathBuf> = match event {
+                    DebouncedEvent::Create(path)
+                    | DebouncedEvent::Write(path)
+                    | DebouncedEvent::Remove(path) => vec![path],
+                    DebouncedEvent::Rename(from, to) => vec![from, to],
+                    DebouncedEvent::Rescan => {
+                        index.invalidate_all();
+                        vec![]
+                    }
+                    DebouncedEvent::Error(err, path) => {
+                        error!("Error watching pin directory: {} ({:?})", err, path);
+                        index.invalidate_all();
+                        vec![]
+                    }
+                    _ => vec![],
+                };
+
+                for path in paths {
+                    let username = path
+                        .strip_prefix(&root)
+                        .ok()
+                        .and_then(|p| p.components().next())
+                        .and_then(|c| c.as_os_str().to_str());
+
+                    match username {
+                        Some(username) => index.invalidate(username),
+                        None => index.invalidate_all(),
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use failure::Error;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::user::UserInfo;
+
+fn redirects_filename(username: &str) -> PathBuf {
+    UserInfo::data_file(username, "pin_redirects.json")
+}
+
+/// Ids of pins merged into others, along with the pin each one was merged into
+pub fn get_redirects(username: &str) -> Result<HashMap<String, String>, Error> {
+    let filename = redirects_filename(username);
+    if !filename.exists() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(serde_json::from_str(&std::fs::read_to_string(filename)?)?)
+}
+
+/// Records that the pins `from_ids` were merged into `to_id`. Pins merged earlier into one of
+/// them now lead to `to_id` directly.
+pub fn add_redirects(username: &str, from_ids: &[String], to_id: &str) -> Result<(), Error> {
+    let mut redirects = get_redirects(username)?;
+
+    for target in redirects.values_mut() {
+        if from_ids.contains(target) {
+            *target = to_id.to_string();
+        }
+    }
+    for id in from_ids {
+        redirects.insert(id.clone(), to_id.to_string());
+    }
+    redirects.remove(to_id);
+
+    save_redirects(username, &redirects)
+}
+
+/// Replaces all of a user's redirects, like when restoring them from an archive
+pub fn save_redirects(username: &str, redirects: &HashMap<String, String>) -> Result<(), Error> {
+    let filename = redirects_filename(username);
+    std::fs::create_dir_all(filename.parent().unwrap())?;
+    std::fs::write(filename, serde_json::to_string(redirects)?)?;
+    Ok(())
+}
+
+/// Pin that `id` was merged into, if it was
+pub fn resolve(username: &str, id: &str) -> Option<String> {
+    get_redirects(username).ok()?.remove(id)
+}
@@ -0,0 +1,58 @@
+use failure::Error;
+use serde::Deserialize;
+
+use crate::importer::{self, ImportedBookmark};
+
+/// A bookmark in Pinboard's JSON export, which kept the field names of the del.icio.us API
+#[derive(Debug, Deserialize)]
+struct PinboardPost {
+    #[serde(default)]
+    href: String,
+    /// The bookmark's title
+    #[serde(default)]
+    description: String,
+    /// The bookmark's notes
+    #[serde(default)]
+    extended: String,
+    #[serde(default)]
+    tags: Tags,
+    #[serde(default)]
+    time: String,
+}
+
+/// Pinboard separates tags with spaces, other services writing the same format use a list
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Tags {
+    Text(String),
+    List(Vec<String>),
+}
+
+impl Default for Tags {
+    fn default() -> Tags {
+        Tags::List(vec![])
+    }
+}
+
+/// Reads the bookmarks in a Pinboard JSON export, or any other file in the format of the
+/// del.icio.us API
+pub fn parse_bookmarks(json: &str) -> Result<Vec<ImportedBookmark>, Error> {
+    let posts: Vec<PinboardPost> = match serde_json::from_str(json.trim_start_matches('\u{feff}')) {
+        Err(err) => bail!("Not a Pinboard JSON export: {}", err),
+        Ok(x) => x,
+    };
+
+    Ok(posts
+        .into_iter()
+        .map(|post| ImportedBookmark {
+            title: post.description,
+            url: post.href,
+            description: post.extended,
+            tags: match post.tags {
+                Tags::Text(tags) => tags.split_whitespace().map(String::from).collect(),
+                Tags::List(tags) => tags,
+            },
+            created: importer::parse_date(&post.time),
+        })
+        .collect())
+}
@@ -1,9 +1,33 @@
 use chrono::prelude::*;
+use chrono::Duration;
 use failure::Error;
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use crate::downloader::DownloadRequest;
+use crate::cache;
+use crate::config::{Config, StorageBackend};
+use crate::download_queue::{DownloadJob, DownloadQueue, JobStatus};
+use crate::downloader::Downloader;
+use crate::fulltext::{FullTextIndex, PageSearchContext};
+use crate::http_client::{HttpClient, UreqClient};
+use crate::importer::{ImportFailure, ImportSummary, ImportedBookmark};
+use crate::json_store::JsonDirStore;
+use crate::link_checker::{LinkChecker, LinkStatus};
+use crate::page_inliner::InlineLimits;
+use crate::page_metadata::PageMetadata;
+use crate::page_renderer::Renderers;
+use crate::pin_index::{PinIndex, UserIndex};
+use crate::pin_redirects;
+use crate::search_query::{SearchContext, SearchQuery};
+use crate::snapshots;
+use crate::sqlite_store::SqliteStore;
+use crate::url_normalizer;
+
+/// Pause between two link checks
+const LINK_CHECK_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Pin {
@@ -14,189 +38,1134 @@ pub struct Pin {
     pub rendered_description: Option<String>,
     pub tags: Vec<String>,
     pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    /// Days between captures of the pin's page, to keep track of how it changes
+    #[serde(default)]
+    pub rearchive_days: Option<u32>,
 }
 
+/// Counts the pins created since startup, so pins created within the same nanosecond, like
+/// during an import, still get different ids
+static CREATED_PINS: AtomicUsize = AtomicUsize::new(0);
+
 impl Pin {
     pub fn new() -> Pin {
         let now = Utc::now();
+        let count = CREATED_PINS.fetch_add(1, Ordering::Relaxed);
         Pin {
-            id: sha1::Sha1::from(format!("{}", now.timestamp_nanos())).hexdigest(),
+            id: sha1::Sha1::from(format!(
+                "{}-{}",
+                now.timestamp_nanos_opt().unwrap_or_default(),
+                count
+            ))
+            .hexdigest(),
             title: String::from(""),
             urls: vec![],
             description: String::new(),
             rendered_description: Some(String::new()),
             tags: vec![],
             created: now,
+            modified: None,
+            rearchive_days: None,
+        }
+    }
+
+    /// Adds another pin's urls and tags to this one, along with its description after this
+    /// one's. The title is only taken if this pin has none, and the oldest creation date is kept.
+    pub fn merge_from(&mut self, other: &Pin) {
+        if self.title.trim().is_empty() {
+            self.title = other.title.clone();
+        }
+
+        let keys: HashSet<String> = self
+            .urls
+            .iter()
+            .map(|url| url_normalizer::duplicate_key(url))
+            .collect();
+        for url in &other.urls {
+            if !keys.contains(&url_normalizer::duplicate_key(url)) {
+                self.urls.push(url.clone());
+            }
+        }
+
+        let description = other.description.trim();
+        if !description.is_empty() && !self.description.contains(description) {
+            if !self.description.trim().is_empty() {
+                self.description.push_str("\n\n");
+            }
+            self.description.push_str(description);
+        }
+
+        self.tags.extend(other.tags.iter().cloned());
+        self.tags.sort_unstable();
+        self.tags.dedup();
+
+        self.created = std::cmp::min(self.created, other.created);
+        self.rearchive_days = match (self.rearchive_days, other.rearchive_days) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub pin: Pin,
+    /// Text around the first match in the pin's cached page, if the page matched at all
+    pub snippet: Option<String>,
+    /// Latest download of the pin's page, to show whether it's still pending or failed
+    pub download: Option<DownloadJob>,
+    /// Whether an icon was saved from the pin's site
+    pub has_favicon: bool,
+    /// Whether the pin's url still worked when last checked
+    pub link_status: Option<LinkStatus>,
+    /// The pin's urls after the first one, each with its own cached page
+    pub extra_urls: Vec<ExtraUrl>,
+}
+
+/// One of a pin's urls other than the first, which has its own cached page and thumbnail
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtraUrl {
+    pub url: String,
+    /// Name of the url's cached files, without their extension
+    pub cache_name: String,
+    pub download: Option<DownloadJob>,
+}
+
+/// Pins with urls leading to the same page
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    pub url: String,
+    pub pins: Vec<Pin>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedPin {
+    pub pin: Pin,
+    pub deleted: DateTime<Utc>,
+}
+
+/// Storage for the pins of every user. Implementations only need to persist pins; URL fix-ups,
+/// markdown rendering and downloads are handled by `BackingStore` on top of them.
+pub trait PinStore: Send + Sync {
+    fn get_pin(&self, username: &str, id: &str) -> Result<Pin, Error>;
+    /// Saves a pin. If the pin already exists, its previous version is kept as an older revision.
+    fn add_pin(&self, username: &str, pin: &Pin) -> Result<(), Error>;
+    /// Saves many pins at once, like during an import
+    fn add_pins(&self, username: &str, pins: &[Pin]) -> Result<(), Error> {
+        for pin in pins {
+            self.add_pin(username, pin)?;
         }
+        Ok(())
+    }
+
+    /// Returns all pins for a user, newest first
+    fn get_all_pins(&self, username: &str) -> Result<Vec<Pin>, Error>;
+
+    /// Returns every saved revision of a pin along with its revision number, oldest first
+    fn get_pin_revisions(&self, username: &str, id: &str) -> Result<Vec<(u32, Pin)>, Error>;
+
+    /// Moves a pin, along with all its revisions, to the trash
+    fn trash_pin(&self, username: &str, id: &str) -> Result<(), Error>;
+    /// Moves a pin back from the trash, returning its latest revision
+    fn restore_pin(&self, username: &str, id: &str) -> Result<Pin, Error>;
+    /// Returns all pins in the trash for a user, most recently deleted first
+    fn get_trashed_pins(&self, username: &str) -> Result<Vec<TrashedPin>, Error>;
+    /// Permanently deletes a pin that's in the trash
+    fn purge_pin(&self, username: &str, id: &str) -> Result<(), Error>;
+
+    /// Directory to watch for changes made behind our back, if the store keeps its data in files
+    /// that can be edited by hand
+    fn watched_directory(&self) -> Option<&Path> {
+        None
     }
 }
 
+/// Returns every tag used in a list of pins along with how many pins use it, sorted by tag name
+pub fn count_tags(pins: &[Pin]) -> Vec<(String, usize)> {
+    let mut result = std::collections::HashMap::<String, usize>::new();
+    pins.iter().flat_map(|p| p.tags.clone()).for_each(|tag| {
+        let counter = result.entry(tag).or_insert(0);
+        *counter += 1;
+    });
+
+    let mut result_vec: Vec<(String, usize)> =
+        result.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    result_vec.sort_by(|a, b| a.0.cmp(&b.0));
+
+    result_vec
+}
+
+/// Turns tags separated by whitespace, as typed in a form, into the tags saved in a pin: lowercase
+/// letters, digits and underscores, sorted and without repeats
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .to_lowercase()
+        .chars()
+        // I believe we could potentially support weird chars, but this'll do for now
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace() || *c == '_')
+        .collect::<String>()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+
+    tags.sort_unstable();
+    tags.dedup();
+    tags
+}
+
+/// Cleans up a pin's urls and renders its description, right before it's saved
+fn fix_up_pin(pin: Pin) -> Pin {
+    let mut pin = pin;
+
+    // Fix up urls
+    pin.urls = pin
+        .urls
+        .iter()
+        .filter(|url| !url.trim().is_empty())
+        .map(|url| url_normalizer::normalize_url(url))
+        .collect();
+    let mut seen = HashSet::new();
+    pin.urls.retain(|url| seen.insert(url.clone()));
+
+    pin.modified = Some(Utc::now());
+
+    // Generate rendered markdown description
+    pin.rendered_description = match super::htmlrenderer::render_markdown_string(&pin.description) {
+        Err(err) => {
+            error!("Error rendering markdown description: {}", err);
+            None
+        }
+        Ok(x) => Some(x),
+    };
+
+    pin
+}
+
 #[derive(Clone)]
 pub struct BackingStore {
-    in_channel: mpsc::Sender<DownloadRequest>,
+    store: Arc<dyn PinStore>,
+    index: PinIndex,
+    fulltext: FullTextIndex,
+    downloads: DownloadQueue,
+    links: LinkChecker,
+    /// Time between the downloads of imported pins
+    import_download_interval: Duration,
+    /// Whether there's any browser to download pages with
+    caching_enabled: bool,
+    /// Held while a pin is saved, so a pin can be read, changed and saved back without
+    /// overwriting a save made in between
+    save_lock: Arc<Mutex<()>>,
+    trash_retention: Duration,
 }
 
 impl BackingStore {
-    pub fn new() -> BackingStore {
-        let (in_channel, out_channel) = mpsc::channel();
-        std::thread::spawn(move || crate::downloader::downloader_thread(out_channel));
+    pub fn new(config: &Config) -> Result<BackingStore, Error> {
+        let store: Arc<dyn PinStore> = match config.storage {
+            StorageBackend::Json => Arc::new(JsonDirStore::new("pins", "trash")),
+            StorageBackend::Sqlite => Arc::new(SqliteStore::open(&config.sqlite_path)?),
+        };
+        info!("Using {:?} pin storage", config.storage);
+
+        let index = PinIndex::new();
+        if let Some(directory) = store.watched_directory() {
+            index.watch_directory(directory)?;
+        }
+
+        let fulltext = FullTextIndex::new();
+
+        let downloads = DownloadQueue::open("download_queue", config.downloads_per_host)?;
+        let renderers = Renderers::new(config.renderer, &config.renderer_fixtures);
+        let caching_enabled = !renderers.is_empty();
+        let browser_timeout = std::time::Duration::from_secs(config.browser_timeout_seconds);
+        let http_client: Arc<dyn HttpClient> = Arc::new(UreqClient::new(browser_timeout));
+        let links = LinkChecker::open(
+            "link_status",
+            http_client.clone(),
+            Duration::days(config.link_check_interval_days),
+        )?;
 
-        BackingStore { in_channel }
+        let backing_store = BackingStore {
+            store,
+            index,
+            fulltext: fulltext.clone(),
+            downloads: downloads.clone(),
+            links,
+            import_download_interval: Duration::seconds(config.import_download_interval_seconds),
+            caching_enabled,
+            save_lock: Arc::new(Mutex::new(())),
+            trash_retention: Duration::days(config.trash_retention_days),
+        };
+
+        let downloader = Downloader {
+            queue: downloads,
+            pins: backing_store.clone(),
+            fulltext,
+            renderers,
+            http_client,
+            inline_limits: Arc::new(InlineLimits {
+                max_asset_size: config.archive_max_asset_size,
+                max_total_size: config.archive_max_page_size,
+                max_time: std::time::Duration::from_secs(config.archive_inline_timeout_seconds),
+            }),
+            browser_timeout,
+            write_warc: config.archive_warc,
+        };
+
+        // Without any browser, pending downloads wait for a restart with one installed
+        let worker_count = if caching_enabled {
+            std::cmp::max(config.download_workers, 1)
+        } else {
+            0
+        };
+        for _ in 0..worker_count {
+            let worker = downloader.clone();
+            std::thread::spawn(move || worker.worker_thread());
+        }
+
+        let purging_store = backing_store.clone();
+        std::thread::spawn(move || purging_store.trash_purging_thread());
+
+        let rearchiving_store = backing_store.clone();
+        std::thread::spawn(move || rearchiving_store.rearchiving_thread());
+
+        if config.link_check_interval_days > 0 {
+            let checking_store = backing_store.clone();
+            std::thread::spawn(move || checking_store.link_checking_thread());
+        }
+
+        Ok(backing_store)
     }
 
+    /// Saves a pin and downloads the pages of its new or changed urls
     pub fn add_pin(&self, username: String, pin: Pin) -> Result<(), Error> {
-        let mut pin = pin;
+        self.add_pin_downloading_at(&username, pin, Utc::now())
+    }
+
+    /// Saves a pin, downloading the pages of its new or changed urls once `download_start` comes
+    fn add_pin_downloading_at(
+        &self,
+        username: &str,
+        pin: Pin,
+        download_start: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        // Urls are only downloaded again when they changed, not on every edit
+        let previous_urls = self.store.get_pin(username, &pin.id).ok().map(|p| p.urls);
+        let pin = self.save_pin(username, pin)?;
 
-        // Fix up url
-        if let Some(url) = pin.urls.first_mut() {
-            let is_url_ok =
-                url.is_empty() || url.starts_with("http://") || url.starts_with("https://");
-            if !is_url_ok {
-                *url = format!("http://{}", url);
+        if self.caching_enabled {
+            for (i, url) in pin.urls.iter().enumerate() {
+                let is_changed = previous_urls.as_ref().and_then(|urls| urls.get(i)) != Some(url);
+                // Like when the pin was saved while caching was disabled
+                let is_never_downloaded =
+                    !cache::has_cached_page(username, &cache::url_basename(&pin.id, i))
+                        && self.downloads.get_job(username, &pin.id, i).is_none();
+
+                if is_changed || is_never_downloaded {
+                    self.downloads
+                        .enqueue_at(username, &pin.id, i, url, download_start)?;
+                }
             }
         }
 
-        // Generate rendered markdown description
-        pin.rendered_description =
-            match super::htmlrenderer::render_markdown_string(&pin.description) {
+        // New pins have nothing cached yet, and neither do pins whose urls didn't change
+        match previous_urls {
+            Some(previous_urls) if previous_urls != pin.urls => {
+                self.downloads
+                    .remove_from(username, &pin.id, pin.urls.len())?;
+
+                // Urls removed from the pin take their cached pages with them
+                cache::remove_extra_url_files(username, &pin.id, pin.urls.len())?;
+                self.fulltext.index_cached_page(username, &pin.id)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a pin for every imported bookmark, skipping the ones whose url was already saved.
+    /// Their downloads are spread out over time, so a big import doesn't hold up everything else.
+    /// A dry run saves nothing, and lists the pins that would be added instead.
+    pub fn import_bookmarks(
+        &self,
+        username: &str,
+        bookmarks: &[ImportedBookmark],
+        dry_run: bool,
+    ) -> Result<ImportSummary, Error> {
+        let mut summary = ImportSummary {
+            dry_run,
+            ..Default::default()
+        };
+
+        let mut saved_urls: HashSet<String> = self
+            .get_all_pins(username)?
+            .iter()
+            .flat_map(|pin| pin.urls.iter())
+            .map(|url| url_normalizer::duplicate_key(url))
+            .collect();
+
+        let mut new_pins = vec![];
+        for bookmark in bookmarks {
+            let pin = match bookmark.to_pin() {
                 Err(err) => {
-                    error!("Error rendering markdown description: {}", err);
-                    None
+                    summary.failed.push(ImportFailure {
+                        entry: bookmark.name(),
+                        error: err.to_string(),
+                    });
+                    continue;
                 }
-                Ok(x) => Some(x),
+                Ok(x) => x,
             };
 
-        let pin_json = serde_json::to_string(&pin).unwrap();
-        let filename = BackingStore::pin_filename("json", &username, &pin.id);
+            if !saved_urls.insert(url_normalizer::duplicate_key(&pin.urls[0])) {
+                summary.skipped_duplicates.push(pin.urls[0].clone());
+                continue;
+            }
 
-        std::fs::create_dir_all(BackingStore::pin_directory(&username))?;
+            new_pins.push(pin);
+        }
+        summary.created = new_pins.len();
 
-        std::fs::write(filename, &pin_json)?;
+        if dry_run {
+            summary.preview = new_pins;
+            return Ok(summary);
+        }
 
-        if !pin.urls.is_empty() {
-            self.in_channel
-                .send(DownloadRequest {
-                    url: pin.urls[0].clone(),
-                    pin_id: pin.id,
-                    username,
-                })
-                .unwrap();
+        self.add_new_pins(username, new_pins, Utc::now())?;
+
+        info!(
+            "Imported {} bookmarks for {}, skipped {} duplicates, {} failed",
+            summary.created,
+            username,
+            summary.skipped_duplicates.len(),
+            summary.failed.len()
+        );
+
+        Ok(summary)
+    }
+
+    /// Saves pins restored from an archive, whose cached files were already put back. Only the
+    /// pages the archive had no copy of are downloaded again, spread out like for an import.
+    /// Each pin comes with its earlier revisions, oldest first, which are saved before it so they
+    /// end up in its history.
+    pub fn add_restored_pins(
+        &self,
+        username: &str,
+        pins: Vec<(Vec<Pin>, Pin)>,
+    ) -> Result<(), Error> {
+        let mut download_start = Utc::now();
+        for (revisions, pin) in pins {
+            for revision in &revisions {
+                self.store.add_pin(username, revision)?;
+            }
+            let pin = self.save_pin(username, pin)?;
+            self.downloads.remove(username, &pin.id)?;
+
+            if self.enqueue_missing_pages(username, &pin, download_start)? {
+                download_start += self.import_download_interval;
+            }
+
+            cache::remove_extra_url_files(username, &pin.id, pin.urls.len())?;
+            self.fulltext.index_cached_page(username, &pin.id)?;
         }
 
         Ok(())
     }
 
-    pub fn delete_pin(&self, username: &str, id: &str) -> Result<(), Error> {
-        let filename = BackingStore::pin_filename("json", &username, id);
+    /// Downloads the pages of a pin's urls that have no cached copy, once `download_start` comes.
+    /// Returns whether any download was queued.
+    fn enqueue_missing_pages(
+        &self,
+        username: &str,
+        pin: &Pin,
+        download_start: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        if !self.caching_enabled {
+            return Ok(false);
+        }
+
+        let mut downloading = false;
+        for (i, url) in pin.urls.iter().enumerate() {
+            if !cache::has_cached_page(username, &cache::url_basename(&pin.id, i)) {
+                self.downloads
+                    .enqueue_at(username, &pin.id, i, url, download_start)?;
+                downloading = true;
+            }
+        }
+
+        Ok(downloading)
+    }
+
+    /// Fixes up and stores a pin, without downloading its page again
+    fn save_pin(&self, username: &str, pin: Pin) -> Result<Pin, Error> {
+        let _guard = self.save_lock.lock().unwrap();
+        self.save_pin_locked(username, pin)
+    }
+
+    /// Same as `save_pin`, for callers already holding `save_lock`
+    fn save_pin_locked(&self, username: &str, pin: Pin) -> Result<Pin, Error> {
+        let pin = fix_up_pin(pin);
+
+        self.store.add_pin(username, &pin)?;
+        self.index.update_pin(username, &pin);
+
+        Ok(pin)
+    }
+
+    /// Saves pins that didn't exist yet all at once, and downloads their pages one pin after the
+    /// other from `download_start` on. Being new, they have no cached pages to look for or index.
+    fn add_new_pins(
+        &self,
+        username: &str,
+        pins: Vec<Pin>,
+        download_start: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let pins: Vec<Pin> = pins.into_iter().map(fix_up_pin).collect();
 
-        if !std::path::Path::new(&filename).exists() {
-            bail!("Pin id {} does not exist", id);
+        {
+            let _guard = self.save_lock.lock().unwrap();
+            self.store.add_pins(username, &pins)?;
+            self.index.update_pins(username, &pins);
         }
 
-        std::fs::remove_file(filename)?;
+        if self.caching_enabled {
+            let mut download_start = download_start;
+            for pin in &pins {
+                for (i, url) in pin.urls.iter().enumerate() {
+                    self.downloads
+                        .enqueue_at(username, &pin.id, i, url, download_start)?;
+                }
+                download_start += self.import_download_interval;
+            }
+        }
 
         Ok(())
     }
 
-    fn pin_filename(extension: &str, username: &str, id: &str) -> String {
-        format!("pins/{}/{}_v0.{}", username, id, extension)
+    /// Fills in a pin's empty title and description with those of the page downloaded from its
+    /// first url, and replaces the downloaded url with the page's canonical one. Whatever the
+    /// user wrote is kept as is. The title and description are only filled in the first time
+    /// the page is cached, so they stay empty if the user cleared them afterwards.
+    pub fn fill_in_page_metadata(
+        &self,
+        job: &DownloadJob,
+        metadata: &PageMetadata,
+        is_first_download: bool,
+    ) -> Result<(), Error> {
+        // The pin is read again, as it may have been edited while its page was downloading
+        let _guard = self.save_lock.lock().unwrap();
+        let mut pin = self.store.get_pin(&job.username, &job.pin_id)?;
+        let mut changed = false;
+
+        if job.url_index == 0 && is_first_download {
+            if let Some(title) = &metadata.title {
+                if pin.title.trim().is_empty() {
+                    pin.title = title.clone();
+                    changed = true;
+                }
+            }
+
+            if let Some(description) = &metadata.description {
+                if pin.description.trim().is_empty() {
+                    pin.description = description.clone();
+                    changed = true;
+                }
+            }
+        }
+
+        if let Some(canonical) = &metadata.canonical_url {
+            let canonical = url_normalizer::normalize_url(canonical);
+            if let Some(url) = pin.urls.get_mut(job.url_index) {
+                if *url == job.url
+                    && *url != canonical
+                    && url_normalizer::is_usable_canonical(url, &canonical)
+                {
+                    info!("Replacing {} with its canonical url {}", url, canonical);
+                    *url = canonical;
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            self.save_pin_locked(&job.username, pin)?;
+        }
+
+        Ok(())
     }
 
-    pub fn pin_directory(username: &str) -> String {
-        format!("pins/{}/", username)
+    /// Moves a pin and its cached files to the trash
+    pub fn delete_pin(&self, username: &str, id: &str) -> Result<(), Error> {
+        self.store.trash_pin(username, id)?;
+        self.index.remove_pin(username, id);
+        self.fulltext.remove_page(username, id);
+        self.downloads.remove(username, id)?;
+        self.links.remove(username, id)?;
+
+        cache::move_cached_files(
+            id,
+            &cache::cache_directory(username),
+            &cache::trash_cache_directory(username),
+        )
     }
 
-    pub fn get_pin(&self, username: &str, id: &str) -> Result<Pin, Error> {
-        let filename = BackingStore::pin_filename("json", username, id);
-        self.get_pin_from_filename(&filename)
+    /// Moves a pin back from the trash, downloading again the pages that weren't cached yet
+    /// when it was deleted
+    pub fn restore_pin(&self, username: &str, id: &str) -> Result<(), Error> {
+        let pin = self.store.restore_pin(username, id)?;
+        self.index.update_pin(username, &pin);
+
+        cache::move_cached_files(
+            id,
+            &cache::trash_cache_directory(username),
+            &cache::cache_directory(username),
+        )?;
+
+        self.enqueue_missing_pages(username, &pin, Utc::now())?;
+        self.fulltext.index_cached_page(username, id)
     }
 
-    pub fn get_pin_from_filename(&self, filename: &str) -> Result<Pin, Error> {
-        let json_data = std::fs::read_to_string(&std::path::Path::new(&filename))?;
-        Ok(serde_json::from_str(&json_data)?)
+    pub fn get_trashed_pins(&self, username: &str) -> Result<Vec<TrashedPin>, Error> {
+        self.store.get_trashed_pins(username)
     }
 
-    pub fn get_all_tags(&self, username: &str) -> Result<Vec<(String, usize)>, Error> {
-        let pins = self.get_all_pins(username)?;
+    /// Permanently deletes every pin in the trash deleted before `cutoff`
+    fn purge_trash(&self, username: &str, cutoff: DateTime<Utc>) -> Result<(), Error> {
+        for trashed_pin in self.store.get_trashed_pins(username)? {
+            if trashed_pin.deleted < cutoff {
+                self.store.purge_pin(username, &trashed_pin.pin.id)?;
+                cache::remove_cached_files(
+                    &trashed_pin.pin.id,
+                    &cache::trash_cache_directory(username),
+                )?;
+            }
+        }
 
-        let mut result = std::collections::HashMap::<String, usize>::new();
-        pins.iter()
-            .map(|p| p.tags.clone())
-            .flatten()
-            .for_each(|tag| {
-                let counter = result.entry(tag).or_insert(0);
-                *counter += 1;
-            });
+        Ok(())
+    }
 
-        let mut result_vec: Vec<(String, usize)> =
-            result.iter().map(|(k, v)| (k.clone(), *v)).collect();
-        result_vec.sort_by(|a, b| a.0.cmp(&b.0));
+    pub fn empty_trash(&self, username: &str) -> Result<(), Error> {
+        self.purge_trash(username, Utc::now())
+    }
 
-        Ok(result_vec)
+    pub fn trash_retention_days(&self) -> i64 {
+        self.trash_retention.num_days()
     }
 
-    pub fn get_all_pins(&self, username: &str) -> Result<Vec<Pin>, Error> {
-        let path_str = BackingStore::pin_directory(username);
-        let dir_path = std::path::Path::new(&path_str);
+    fn trash_purging_thread(&self) {
+        loop {
+            let cutoff = Utc::now() - self.trash_retention;
+
+            match crate::user::UserInfo::all_usernames() {
+                Err(err) => error!("Could not list users to purge their trash: {}", err),
+                Ok(usernames) => {
+                    for username in usernames {
+                        if let Err(err) = self.purge_trash(&username, cutoff) {
+                            error!("Error purging trash for user {}: {}", username, err);
+                        }
+                    }
+                }
+            }
 
-        if !dir_path.exists() {
-            return Ok(vec![]);
+            std::thread::sleep(std::time::Duration::from_secs(60 * 60));
         }
+    }
 
-        let pins_res: Result<Vec<Pin>, Error> = std::fs::read_dir(dir_path)?
-            .filter(|file| {
-                if !file.is_ok() {
-                    return false;
+    /// Checks the urls of a user's pins that weren't checked recently
+    fn check_links(&self, username: &str) -> Result<(), Error> {
+        let now = Utc::now();
+
+        for pin in self.get_all_pins(username)? {
+            if !self.links.is_due(username, &pin, now) {
+                continue;
+            }
+
+            if let Err(err) = self.links.check_pin(username, &pin) {
+                error!("Error checking link of pin {}: {}", pin.id, err);
+            }
+
+            // Checks are spread out, so sites with many pins don't get a burst of requests
+            std::thread::sleep(LINK_CHECK_DELAY);
+        }
+
+        Ok(())
+    }
+
+    fn link_checking_thread(&self) {
+        loop {
+            match crate::user::UserInfo::all_usernames() {
+                Err(err) => error!("Could not list users to check their links: {}", err),
+                Ok(usernames) => {
+                    for username in usernames {
+                        if let Err(err) = self.check_links(&username) {
+                            error!("Error checking links for user {}: {}", username, err);
+                        }
+                    }
                 }
-                let file = file.as_ref().unwrap();
-                let path = file.path();
-                let extension = path.as_path().extension();
-                if extension.is_none() {
-                    return false;
+            }
+
+            std::thread::sleep(std::time::Duration::from_secs(60 * 60));
+        }
+    }
+
+    /// Downloads again the pages of a user's pins that are set to be captured periodically and
+    /// are due for it
+    fn rearchive_pins(&self, username: &str) -> Result<(), Error> {
+        let tag_intervals = snapshots::get_tag_intervals(username)?;
+        let now = Utc::now();
+
+        for pin in self.get_all_pins(username)? {
+            let days = match snapshots::rearchive_interval(&pin, &tag_intervals) {
+                None => continue,
+                Some(x) => x,
+            };
+            if pin.urls.first().is_none_or(|url| url.is_empty()) {
+                continue;
+            }
+
+            // A failed or still pending capture counts too, so broken pages aren't retried
+            // over and over
+            let job = self.downloads.get_job(username, &pin.id, 0);
+            if job.as_ref().is_some_and(|j| j.status == JobStatus::Pending) {
+                continue;
+            }
+            let last_snapshot = snapshots::get_timeline(username, &pin.id)?
+                .last()
+                .map(|s| s.last_seen);
+            let last_capture = std::cmp::max(last_snapshot, job.map(|j| j.queued));
+
+            if last_capture.is_none_or(|date| now - date >= Duration::days(i64::from(days))) {
+                self.downloads.enqueue(username, &pin.id, 0, &pin.urls[0])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rearchiving_thread(&self) {
+        loop {
+            if self.caching_enabled {
+                match crate::user::UserInfo::all_usernames() {
+                    Err(err) => error!("Could not list users to capture their pages: {}", err),
+                    Ok(usernames) => {
+                        for username in usernames {
+                            if let Err(err) = self.rearchive_pins(&username) {
+                                error!("Error capturing pages for user {}: {}", username, err);
+                            }
+                        }
+                    }
                 }
-                extension.unwrap() == "json"
+            }
+
+            std::thread::sleep(std::time::Duration::from_secs(60 * 60));
+        }
+    }
+
+    /// Pins with an url leading to the same page as any of `urls`
+    pub fn find_duplicates_of(&self, username: &str, urls: &[String]) -> Result<Vec<Pin>, Error> {
+        let keys: HashSet<String> = urls
+            .iter()
+            .filter(|url| !url.trim().is_empty())
+            .map(|url| url_normalizer::duplicate_key(url))
+            .collect();
+
+        Ok(self
+            .get_all_pins(username)?
+            .into_iter()
+            .filter(|pin| {
+                pin.urls
+                    .iter()
+                    .any(|url| keys.contains(&url_normalizer::duplicate_key(url)))
             })
-            .map(|file| {
-                let file = file.unwrap();
-                self.get_pin_from_filename(&file.path().as_path().to_str().unwrap())
+            .collect())
+    }
+
+    /// Every group of a user's pins sharing an url, including pins saved before duplicates
+    /// were looked for. Pins sharing several urls are only reported once.
+    pub fn find_duplicates(&self, username: &str) -> Result<Vec<DuplicateGroup>, Error> {
+        let mut groups = std::collections::BTreeMap::<String, DuplicateGroup>::new();
+        for pin in self.get_all_pins(username)? {
+            let mut pin_keys = HashSet::new();
+            for url in &pin.urls {
+                let key = url_normalizer::duplicate_key(url);
+                if !pin_keys.insert(key.clone()) {
+                    continue;
+                }
+
+                groups
+                    .entry(key)
+                    .or_insert_with(|| DuplicateGroup {
+                        url: url.clone(),
+                        pins: vec![],
+                    })
+                    .pins
+                    .push(pin.clone());
+            }
+        }
+
+        let mut reported = HashSet::new();
+        Ok(groups
+            .into_values()
+            .filter(|group| group.pins.len() > 1)
+            .filter(|group| {
+                let ids: Vec<String> = group.pins.iter().map(|p| p.id.clone()).collect();
+                reported.insert(ids)
             })
-            .collect();
+            .collect())
+    }
 
-        if pins_res.is_err() {
-            return pins_res;
+    /// Merges pins into the first of `ids`, which gets the urls, tags and descriptions of all
+    /// of them, the oldest creation date and every snapshot. The others are moved to the trash,
+    /// and their ids lead to the merged pin from then on.
+    pub fn merge_pins(&self, username: &str, ids: &[String]) -> Result<Pin, Error> {
+        let mut merged = match ids.first() {
+            Some(id) => self.get_pin(username, id)?,
+            None => bail!("No pins to merge"),
+        };
+
+        let mut merged_ids: Vec<String> = vec![];
+        for id in &ids[1..] {
+            if *id != merged.id && !merged_ids.contains(id) {
+                merged_ids.push(id.clone());
+            }
         }
+        ensure!(
+            !merged_ids.is_empty(),
+            "At least two pins are needed to merge them"
+        );
+
+        // Every pin is read before any file is moved, so a missing one doesn't leave a half merge
+        let pins = merged_ids
+            .iter()
+            .map(|id| self.get_pin(username, id))
+            .collect::<Result<Vec<Pin>, Error>>()?;
+
+        for pin in &pins {
+            // Urls new to the merged pin keep their cached pages
+            let first_new_url = merged.urls.len();
+            merged.merge_from(pin);
+            for (i, url) in pin.urls.iter().enumerate() {
+                if let Some(j) = merged.urls[first_new_url..].iter().position(|u| u == url) {
+                    cache::move_url_files(username, (&pin.id, i), (&merged.id, first_new_url + j))?;
+                }
+            }
+
+            snapshots::merge_timelines(username, &merged.id, &pin.id)?;
+        }
+
+        // Pages carried over from the other pins are kept, only the missing ones are downloaded
+        let merged = self.save_pin(username, merged)?;
+        self.enqueue_missing_pages(username, &merged, Utc::now())?;
+        self.fulltext.index_cached_page(username, &merged.id)?;
+
+        for id in &merged_ids {
+            self.delete_pin(username, id)?;
+        }
+        pin_redirects::add_redirects(username, &merged_ids, &merged.id)?;
+
+        Ok(merged)
+    }
+
+    /// Pin that the pin `id` was merged into, if it was
+    pub fn merged_into(&self, username: &str, id: &str) -> Option<String> {
+        pin_redirects::resolve(username, id)
+    }
+
+    /// Adds a new pin's urls, tags and description to an existing pin instead of saving it
+    /// separately
+    pub fn merge_into_pin(&self, username: &str, id: &str, pin: &Pin) -> Result<(), Error> {
+        let mut existing = self.get_pin(username, id)?;
+        existing.merge_from(pin);
+        self.add_pin(username.to_string(), existing)
+    }
 
-        let mut pins = pins_res.unwrap();
-        pins.sort_by(|a, b| b.created.cmp(&a.created));
+    pub fn get_link_status(&self, username: &str, id: &str) -> Option<LinkStatus> {
+        self.links.get_status(username, id)
+    }
 
-        Ok(pins)
+    pub fn get_pin(&self, username: &str, id: &str) -> Result<Pin, Error> {
+        self.store.get_pin(username, id)
     }
 
-    pub fn search_pins(&self, username: &str, search_pattern: &str) -> Result<Vec<Pin>, Error> {
-        let pins = self.get_all_pins(username)?;
+    pub fn get_pin_revisions(&self, username: &str, id: &str) -> Result<Vec<(u32, Pin)>, Error> {
+        self.store.get_pin_revisions(username, id)
+    }
 
-        let search_pattern = search_pattern.to_lowercase();
-        let search_terms = search_pattern.split_whitespace();
+    /// Saves an older revision of a pin as its newest one. Nothing is lost, as the version being
+    /// replaced stays in the pin's history.
+    pub fn restore_revision(&self, username: &str, id: &str, revision: u32) -> Result<(), Error> {
+        let pin = match self
+            .get_pin_revisions(username, id)?
+            .into_iter()
+            .find(|(number, _)| *number == revision)
+        {
+            None => bail!("Pin id {} has no revision {}", id, revision),
+            Some((_, pin)) => pin,
+        };
+
+        self.add_pin(username.to_string(), pin)
+    }
 
-        Ok(pins
+    /// Latest download of a pin's first url
+    pub fn get_download(&self, username: &str, id: &str) -> Option<DownloadJob> {
+        self.downloads.get_job(username, id, 0)
+    }
+
+    /// Wraps a pin with what's known about its downloads and links, to be shown in a list
+    pub fn search_result(&self, username: &str, pin: Pin, snippet: Option<String>) -> SearchResult {
+        let extra_urls = pin
+            .urls
             .iter()
-            .filter(|p| {
-                let title = p.title.to_lowercase();
+            .enumerate()
+            .skip(1)
+            .map(|(i, url)| ExtraUrl {
+                url: url.clone(),
+                cache_name: cache::url_basename(&pin.id, i),
+                download: self.downloads.get_job(username, &pin.id, i),
+            })
+            .collect();
 
-                title.contains(&search_pattern)
-                    || p.urls.iter().any(|u| {
-                        let url = u.to_lowercase();
-                        search_terms.clone().all(|term| url.contains(term))
-                    })
-                    || search_terms
-                        .clone()
-                        .all(|term| p.description.to_lowercase().contains(term))
-                    || p.tags.iter().any(|tag| {
-                        let tag = tag.to_lowercase();
-                        search_terms.clone().all(|term| tag.contains(term))
-                    })
+        SearchResult {
+            snippet,
+            download: self.get_download(username, &pin.id),
+            has_favicon: cache::has_favicon(username, &pin.id),
+            link_status: self.get_link_status(username, &pin.id),
+            extra_urls,
+            pin,
+        }
+    }
+
+    /// Returns the in-memory pins and tags for a user, loading them from storage if needed
+    fn get_user_index(&self, username: &str) -> Result<Arc<UserIndex>, Error> {
+        self.index.get_or_load(username, || {
+            self.fulltext.load_in_background(username);
+            self.store.get_all_pins(username)
+        })
+    }
+
+    pub fn get_all_tags(&self, username: &str) -> Result<Vec<(String, usize)>, Error> {
+        Ok(self.get_user_index(username)?.tags.clone())
+    }
+
+    pub fn get_all_pins(&self, username: &str) -> Result<Vec<Pin>, Error> {
+        Ok(self.get_user_index(username)?.pins.clone())
+    }
+
+    /// Returns the pins matching a query, best matches first. Without any free text to rank by,
+    /// they're kept newest first.
+    pub fn search_pins(
+        &self,
+        username: &str,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchResult>, Error> {
+        let user_index = self.get_user_index(username)?;
+        let ranking_terms = query.text_terms(false);
+
+        self.fulltext.with_user(username, |text_index| {
+            let context =
+                PageSearchContext::new(username, text_index, &query.text_terms(true), &self.links);
+
+            let mut results: Vec<(f32, SearchResult)> = user_index
+                .pins
+                .iter()
+                .filter(|p| query.matches(p, &context))
+                .map(|pin| {
+                    let page_matched = ranking_terms
+                        .iter()
+                        .any(|term| context.page_contains(pin, term));
+                    let snippet = if page_matched {
+                        text_index.snippet(&pin.id, &ranking_terms)
+                    } else {
+                        None
+                    };
+
+                    (
+                        text_index.score(pin, &ranking_terms),
+                        self.search_result(username, pin.clone(), snippet),
+                    )
+                })
+                .collect();
+
+            // The sort is stable, so equally ranked pins stay newest first
+            results.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+            results.into_iter().map(|(_, result)| result).collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page_renderer::RendererChoice;
+    use crate::test_support::fake_storage;
+
+    /// Waits for the download of one of a pin's urls to be done
+    fn wait_for_download(
+        storage: &BackingStore,
+        username: &str,
+        id: &str,
+        url_index: usize,
+    ) -> DownloadJob {
+        let started = std::time::Instant::now();
+        loop {
+            let job = storage.downloads.get_job(username, id, url_index).unwrap();
+            if job.status != JobStatus::Pending {
+                return job;
+            }
+            assert!(
+                started.elapsed() < std::time::Duration::from_secs(30),
+                "download never finished"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn added_pin_is_downloaded_by_the_fake_renderer() {
+        let storage = fake_storage();
+
+        let mut pin = Pin::new();
+        pin.title = "Fake page".into();
+        // Nothing listens there, the fake renderer doesn't need it to
+        pin.urls = vec!["http://127.0.0.1:9/page".into()];
+        let id = pin.id.clone();
+        storage.add_pin("pipeline".into(), pin).unwrap();
+
+        let job = wait_for_download(&storage, "pipeline", &id, 0);
+        assert_eq!(job.status, JobStatus::Ok, "{:?}", job.last_error);
+        let directory = cache::cache_directory("pipeline");
+        assert!(directory.join(format!("{}.html", id)).exists());
+        assert!(directory.join(format!("{}.jpg", id)).exists());
+    }
+
+    #[test]
+    fn edits_only_download_changed_urls() {
+        let storage = fake_storage();
+        let username = "edits";
+
+        let mut pin = Pin::new();
+        pin.urls = vec!["http://127.0.0.1:9/first".into()];
+        storage.add_pin(username.into(), pin.clone()).unwrap();
+        let first_download = wait_for_download(&storage, username, &pin.id, 0);
+
+        pin.tags = vec!["unrelated".into()];
+        storage.add_pin(username.into(), pin.clone()).unwrap();
+        let job = storage.downloads.get_job(username, &pin.id, 0).unwrap();
+        assert_eq!(job.queued, first_download.queued);
+
+        pin.urls.push("http://127.0.0.1:9/second".into());
+        storage.add_pin(username.into(), pin.clone()).unwrap();
+        wait_for_download(&storage, username, &pin.id, 1);
+        let job = storage.downloads.get_job(username, &pin.id, 0).unwrap();
+        assert_eq!(job.queued, first_download.queued);
+
+        pin.urls[0] = "http://127.0.0.1:9/changed".into();
+        storage.add_pin(username.into(), pin.clone()).unwrap();
+        let job = wait_for_download(&storage, username, &pin.id, 0);
+        assert!(job.queued > first_download.queued);
+        assert_eq!(job.url, "http://127.0.0.1:9/changed");
+    }
+
+    #[test]
+    fn merged_pins_keep_their_pages() {
+        let storage = fake_storage();
+        let username = "merges";
+
+        let mut pins = vec![];
+        for path in &["one", "two"] {
+            let mut pin = Pin::new();
+            pin.urls = vec![format!("http://127.0.0.1:9/{}", path)];
+            storage.add_pin(username.into(), pin.clone()).unwrap();
+            wait_for_download(&storage, username, &pin.id, 0);
+            pins.push(pin);
+        }
+        let first_download = storage.downloads.get_job(username, &pins[0].id, 0).unwrap();
+
+        // A pin that doesn't exist stops the merge before anything is moved
+        let ids = vec![pins[0].id.clone(), pins[1].id.clone(), "missing".into()];
+        assert!(storage.merge_pins(username, &ids).is_err());
+        assert!(cache::has_cached_page(username, &pins[1].id));
+
+        let merged = storage.merge_pins(username, &ids[..2]).unwrap();
+        assert_eq!(merged.urls.len(), 2);
+        assert!(cache::has_cached_page(username, &merged.id));
+        assert!(cache::has_cached_page(
+            username,
+            &cache::url_basename(&merged.id, 1)
+        ));
+
+        let job = storage.downloads.get_job(username, &merged.id, 0).unwrap();
+        assert_eq!(job.queued, first_download.queued);
+        assert!(storage.downloads.get_job(username, &merged.id, 1).is_none());
+    }
+
+    #[test]
+    fn imported_pins_get_their_own_ids_and_staggered_downloads() {
+        let storage = fake_storage();
+        let username = "imports";
+
+        let bookmarks: Vec<ImportedBookmark> = (0..200)
+            .map(|i| ImportedBookmark {
+                title: format!("Bookmark {}", i),
+                url: format!("http://127.0.0.1:9/{}", i),
+                description: String::new(),
+                tags: vec![],
+                created: None,
             })
-            .cloned()
-            .collect())
+            .collect();
+
+        let summary = storage
+            .import_bookmarks(username, &bookmarks, false)
+            .unwrap();
+        assert_eq!(summary.created, 200);
+
+        let pins = storage.get_all_pins(username).unwrap();
+        let ids: HashSet<&str> = pins.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids.len(), 200);
+
+        // The first download may be over already, which moves its next attempt
+        let mut starts: Vec<DateTime<Utc>> = pins
+            .iter()
+            .filter_map(|p| storage.downloads.get_job(username, &p.id, 0))
+            .map(|job| job.next_attempt)
+            .collect();
+        assert_eq!(starts.len(), 200);
+        starts.sort();
+        assert!(starts[199] - starts[1] >= storage.import_download_interval * 198);
+
+        let summary = storage
+            .import_bookmarks(username, &bookmarks, false)
+            .unwrap();
+        assert_eq!(summary.created, 0);
+        assert_eq!(summary.skipped_duplicates.len(), 200);
+    }
+
+    #[test]
+    fn no_warc_file_is_saved_without_the_page() {
+        crate::test_support::use_temp_directory();
+        let config = Config {
+            renderer: RendererChoice::Fake,
+            renderer_fixtures: crate::test_support::fixture("renderer")
+                .to_string_lossy()
+                .into_owned(),
+            link_check_interval_days: 0,
+            archive_warc: true,
+            ..Default::default()
+        };
+        let storage = BackingStore::new(&config).unwrap();
+        let username = "warcs";
+
+        // The fake renderer has the page, but nothing answers the WARC's own request for it
+        let mut pin = Pin::new();
+        pin.urls = vec!["http://127.0.0.1:9/unreachable".into()];
+        storage.add_pin(username.into(), pin.clone()).unwrap();
+        let job = wait_for_download(&storage, username, &pin.id, 0);
+
+        assert_eq!(job.status, JobStatus::Ok, "{:?}", job.last_error);
+        let directory = cache::cache_directory(username);
+        assert!(directory.join(format!("{}.html", pin.id)).exists());
+        assert!(!directory.join(format!("{}.warc", pin.id)).exists());
     }
 }
@@ -0,0 +1,426 @@
+use html5ever::rcdom::{Handle, Node, NodeData, RcDom};
+use html5ever::tendril::TendrilSink;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::htmlrenderer::escape_html;
+
+/// Elements that never hold any of the article, dropped along with everything in them
+const REMOVED_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "head", "nav", "footer", "aside", "form", "iframe",
+    "button", "input", "select", "textarea", "svg", "canvas", "object", "embed",
+];
+
+/// Words in a class or id that hint at an element being clutter around the article
+const UNLIKELY_WORDS: &[&str] = &[
+    "ad",
+    "ads",
+    "advert",
+    "advertisement",
+    "banner",
+    "breadcrumb",
+    "breadcrumbs",
+    "comment",
+    "comments",
+    "cookie",
+    "disqus",
+    "footer",
+    "masthead",
+    "menu",
+    "nav",
+    "navbar",
+    "newsletter",
+    "popup",
+    "promo",
+    "related",
+    "share",
+    "sharing",
+    "sidebar",
+    "social",
+    "sponsor",
+    "sponsored",
+    "subscribe",
+    "widget",
+];
+
+/// Words in a class or id that hint at an element being the article itself
+const LIKELY_WORDS: &[&str] = &[
+    "article", "body", "content", "entry", "main", "post", "story", "text",
+];
+
+/// Elements kept in the extracted article. Anything else is replaced by its contents.
+const KEPT_TAGS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "li",
+    "dl",
+    "dt",
+    "dd",
+    "blockquote",
+    "pre",
+    "code",
+    "em",
+    "strong",
+    "i",
+    "b",
+    "sub",
+    "sup",
+    "a",
+    "img",
+    "figure",
+    "figcaption",
+    "br",
+    "hr",
+    "table",
+    "thead",
+    "tbody",
+    "tr",
+    "th",
+    "td",
+];
+
+const VOID_TAGS: &[&str] = &["img", "br", "hr"];
+
+/// Paragraphs shorter than this don't count towards the score of the elements around them
+const MIN_PARAGRAPH_LENGTH: usize = 25;
+
+fn tag_name(node: &Handle) -> Option<String> {
+    match node.data {
+        NodeData::Element { ref name, .. } => Some(name.local.to_ascii_lowercase().to_string()),
+        _ => None,
+    }
+}
+
+fn attribute(node: &Handle, name: &str) -> Option<String> {
+    match node.data {
+        NodeData::Element { ref attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|a| a.name.local.eq_str_ignore_ascii_case(name))
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+/// The words making up an element's class and id, like `post` and `body` for `post-body`
+fn class_words(node: &Handle) -> Vec<String> {
+    let class_and_id = format!(
+        "{} {}",
+        attribute(node, "class").unwrap_or_default(),
+        attribute(node, "id").unwrap_or_default()
+    );
+
+    class_and_id
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+fn class_weight(node: &Handle) -> f32 {
+    let words = class_words(node);
+    let mut weight = 0.0;
+    if words.iter().any(|w| LIKELY_WORDS.contains(&w.as_str())) {
+        weight += 25.0;
+    }
+    if words.iter().any(|w| UNLIKELY_WORDS.contains(&w.as_str())) {
+        weight -= 25.0;
+    }
+    weight
+}
+
+fn is_unlikely(node: &Handle) -> bool {
+    match tag_name(node).as_deref() {
+        None => false,
+        Some("html") | Some("body") | Some("article") | Some("main") => false,
+        Some(tag) if REMOVED_TAGS.contains(&tag) => true,
+        Some(_) => class_weight(node) < 0.0,
+    }
+}
+
+/// Removes comments, and elements that can't be part of the article
+fn remove_clutter(node: &Handle) {
+    node.children.borrow_mut().retain(|child| match child.data {
+        NodeData::Comment { .. } | NodeData::ProcessingInstruction { .. } => false,
+        _ => !is_unlikely(child),
+    });
+
+    for child in node.children.borrow().iter() {
+        remove_clutter(child);
+    }
+}
+
+fn text_content(node: &Handle, text: &mut String) {
+    if let NodeData::Text { ref contents } = node.data {
+        text.push_str(&contents.borrow());
+    }
+
+    for child in node.children.borrow().iter() {
+        text_content(child, text);
+    }
+}
+
+fn text_length(node: &Handle) -> usize {
+    let mut text = String::new();
+    text_content(node, &mut text);
+    text.split_whitespace().map(|w| w.chars().count() + 1).sum()
+}
+
+/// How much of an element's text is in links. Menus and lists of related articles are mostly
+/// links, while articles mostly aren't.
+fn link_density(node: &Handle) -> f32 {
+    fn link_length(node: &Handle) -> usize {
+        if tag_name(node).as_deref() == Some("a") {
+            return text_length(node);
+        }
+        node.children.borrow().iter().map(link_length).sum()
+    }
+
+    let total = text_length(node);
+    if total == 0 {
+        return 0.0;
+    }
+    link_length(node) as f32 / total as f32
+}
+
+/// Score an element starts with, before the paragraphs in it are counted
+fn initial_score(node: &Handle) -> f32 {
+    let tag_score = match tag_name(node).as_deref() {
+        Some("article") => 10.0,
+        Some("div") | Some("section") | Some("main") => 5.0,
+        Some("pre") | Some("td") | Some("blockquote") => 3.0,
+        Some("ol") | Some("ul") | Some("dl") | Some("dd") | Some("dt") | Some("li") => -3.0,
+        Some("h1") | Some("h2") | Some("h3") | Some("h4") | Some("h5") | Some("h6")
+        | Some("th") => -5.0,
+        _ => 0.0,
+    };
+
+    tag_score + class_weight(node)
+}
+
+/// Whether an element holds text directly rather than through other blocks, like a `<div>`
+/// used as a paragraph
+fn is_paragraph(node: &Handle) -> bool {
+    const BLOCKS: &[&str] = &[
+        "p",
+        "div",
+        "section",
+        "article",
+        "table",
+        "ul",
+        "ol",
+        "dl",
+        "blockquote",
+        "pre",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "figure",
+    ];
+
+    match tag_name(node).as_deref() {
+        Some("p") | Some("pre") | Some("td") => true,
+        Some("div") => !node
+            .children
+            .borrow()
+            .iter()
+            .any(|child| tag_name(child).is_some_and(|tag| BLOCKS.contains(&tag.as_str()))),
+        _ => false,
+    }
+}
+
+type Scores = HashMap<*const Node, (Handle, f32)>;
+
+/// Gives every paragraph's parent and grandparents points for it, the more the longer it is
+fn score_paragraphs(node: &Handle, ancestors: &mut Vec<Handle>, scores: &mut Scores) {
+    if is_paragraph(node) {
+        let mut text = String::new();
+        text_content(node, &mut text);
+        let length = text_length(node);
+
+        if length >= MIN_PARAGRAPH_LENGTH {
+            let commas = text.matches(',').count();
+            let score = 1.0 + commas as f32 + std::cmp::min(length / 100, 3) as f32;
+
+            for (level, ancestor) in ancestors.iter().rev().take(3).enumerate() {
+                let divider = match level {
+                    0 => 1.0,
+                    1 => 2.0,
+                    _ => level as f32 * 3.0,
+                };
+                let entry = scores
+                    .entry(Rc::as_ptr(ancestor))
+                    .or_insert_with(|| (ancestor.clone(), initial_score(ancestor)));
+                entry.1 += score / divider;
+            }
+        }
+    }
+
+    ancestors.push(node.clone());
+    for child in node.children.borrow().iter() {
+        score_paragraphs(child, ancestors, scores);
+    }
+    ancestors.pop();
+}
+
+fn find_body(node: &Handle) -> Option<Handle> {
+    if tag_name(node).as_deref() == Some("body") {
+        return Some(node.clone());
+    }
+
+    node.children.borrow().iter().find_map(find_body)
+}
+
+/// Elements making up the article: the best scoring one, and its siblings that look like they
+/// belong with it, like an introduction kept apart from the rest
+fn article_elements(document: &Handle) -> Vec<Handle> {
+    let mut scores = Scores::new();
+    score_paragraphs(document, &mut vec![], &mut scores);
+
+    let best = scores
+        .values()
+        .map(|(node, score)| (node, score * (1.0 - link_density(node))))
+        .filter(|(node, _)| tag_name(node).is_some())
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    let (best, best_score) = match best {
+        None => return find_body(document).into_iter().collect(),
+        Some((node, score)) => (node.clone(), score),
+    };
+
+    let parent = best.parent.take();
+    best.parent.set(parent.clone());
+    let parent = match parent.and_then(|p| p.upgrade()) {
+        None => return vec![best],
+        Some(x) => x,
+    };
+
+    let sibling_threshold = f32::max(10.0, best_score * 0.2);
+    let siblings = parent.children.borrow();
+    siblings
+        .iter()
+        .filter(|sibling| {
+            if Rc::ptr_eq(sibling, &best) {
+                return true;
+            }
+            if let Some((_, score)) = scores.get(&Rc::as_ptr(sibling)) {
+                if *score >= sibling_threshold {
+                    return true;
+                }
+            }
+            tag_name(sibling).as_deref() == Some("p")
+                && text_length(sibling) > 80
+                && link_density(sibling) < 0.25
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether a container inside the article is mostly links, like a list of related articles
+fn is_link_list(node: &Handle, tag: &str) -> bool {
+    fn has_image(node: &Handle) -> bool {
+        tag_name(node).as_deref() == Some("img") || node.children.borrow().iter().any(has_image)
+    }
+
+    ["div", "section", "ul", "ol", "table"].contains(&tag)
+        && link_density(node) > 0.5
+        && !has_image(node)
+}
+
+/// Writes an element's html keeping only the tags and attributes of `KEPT_TAGS` that are
+/// safe to show within our own pages
+fn write_clean_html(node: &Handle, out: &mut String) {
+    match node.data {
+        NodeData::Text { ref contents } => out.push_str(&escape_html(&contents.borrow())),
+        NodeData::Element { .. } => {
+            let tag = tag_name(node).unwrap_or_default();
+            if is_link_list(node, &tag) {
+                return;
+            }
+
+            let is_kept = KEPT_TAGS.contains(&tag.as_str());
+            if is_kept {
+                out.push('<');
+                out.push_str(&tag);
+
+                let kept_attributes: &[&str] = match tag.as_ref() {
+                    "a" => &["href"],
+                    "img" => &["src", "alt"],
+                    _ => &[],
+                };
+                for name in kept_attributes {
+                    let value = match attribute(node, name) {
+                        None => continue,
+                        Some(x) => x,
+                    };
+                    let lowercase = value.trim().to_lowercase();
+                    let is_safe = match *name {
+                        "href" => ["http:", "https:", "mailto:"]
+                            .iter()
+                            .any(|scheme| lowercase.starts_with(scheme)),
+                        "src" => ["http:", "https:", "data:image/"]
+                            .iter()
+                            .any(|scheme| lowercase.starts_with(scheme)),
+                        _ => true,
+                    };
+                    if is_safe {
+                        out.push_str(&format!(" {}=\"", name));
+                        out.push_str(&escape_html(&value));
+                        out.push('"');
+                    }
+                }
+                out.push('>');
+
+                if VOID_TAGS.contains(&tag.as_str()) {
+                    return;
+                }
+            }
+
+            for child in node.children.borrow().iter() {
+                write_clean_html(child, out);
+            }
+
+            if is_kept {
+                out.push_str(&format!("</{}>", tag));
+            }
+        }
+        _ => {
+            for child in node.children.borrow().iter() {
+                write_clean_html(child, out);
+            }
+        }
+    }
+}
+
+/// Finds the main article of a page and returns it as simple html, with the navigation, ads,
+/// comments and such around it left out. Returns `None` if there's no text to be found.
+pub fn extract_article(html: &[u8]) -> Option<String> {
+    let dom = html5ever::parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .read_from(&mut &html[..])
+        .ok()?;
+
+    remove_clutter(&dom.document);
+
+    let elements = article_elements(&dom.document);
+    if elements.iter().map(text_length).sum::<usize>() == 0 {
+        return None;
+    }
+
+    let mut article = String::new();
+    for element in elements {
+        write_clean_html(&element, &mut article);
+    }
+
+    Some(article)
+}
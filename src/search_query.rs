@@ -0,0 +1,305 @@
+use chrono::prelude::*;
+use failure::Error;
+
+use crate::link_checker::LinkStatus;
+use crate::pins::Pin;
+
+/// Things a pin can be required to have with `has:`
+#[derive(Debug, Clone, PartialEq)]
+pub enum HasTarget {
+    Cache,
+    Description,
+    Tags,
+    Url,
+}
+
+/// States of a pin's link that can be searched for with `is:`
+#[derive(Debug, Clone, PartialEq)]
+pub enum IsTarget {
+    /// The url looked dead when last checked
+    Broken,
+    /// The url redirects somewhere else than it used to
+    Moved,
+}
+
+/// A parsed search query. Free text terms match the title, urls, description or tags of a pin,
+/// while the rest only look at a single field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchQuery {
+    And(Vec<SearchQuery>),
+    Or(Vec<SearchQuery>),
+    Not(Box<SearchQuery>),
+    Text(String),
+    Tag(String),
+    Url(String),
+    Title(String),
+    Description(String),
+    /// Created strictly before the start of that day
+    Before(DateTime<Utc>),
+    /// Created on that day or later
+    After(DateTime<Utc>),
+    Has(HasTarget),
+    Is(IsTarget),
+}
+
+/// What a query needs to know about a pin that can't be read from the pin itself
+pub trait SearchContext {
+    /// Whether a cached copy of the pin's page exists
+    fn has_cache(&self, pin: &Pin) -> bool;
+    /// Outcome of the latest check of the pin's url, if it was checked
+    fn link_status(&self, pin: &Pin) -> Option<LinkStatus>;
+    /// Whether the text of the pin's cached page contains every word of `text`
+    fn page_contains(&self, pin: &Pin, text: &str) -> bool;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    OpenParen,
+    CloseParen,
+    Or,
+    Not,
+    Word(String),
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        match c {
+            '(' => {
+                chars.next();
+                tokens.push(Token::OpenParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::CloseParen);
+            }
+            _ => {
+                // A dash only negates when it's directly attached to what follows it
+                if c == '-' {
+                    chars.next();
+                    match chars.peek() {
+                        Some(next) if !next.is_whitespace() && *next != ')' => {
+                            tokens.push(Token::Not);
+                            continue;
+                        }
+                        _ => {
+                            tokens.push(Token::Word(String::from("-")));
+                            continue;
+                        }
+                    }
+                }
+
+                let mut word = String::new();
+                let mut in_quotes = false;
+                while let Some(&c) = chars.peek() {
+                    if !in_quotes && (c.is_whitespace() || c == '(' || c == ')') {
+                        break;
+                    }
+                    if c == '"' {
+                        in_quotes = !in_quotes;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+
+                ensure!(!in_quotes, "Unterminated quote in search query");
+
+                if word == "OR" {
+                    tokens.push(Token::Or);
+                } else {
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>, Error> {
+    let date = match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Err(_) => bail!(
+            "Invalid date \"{}\", dates must look like 2019-06-01",
+            value
+        ),
+        Ok(x) => x,
+    };
+
+    Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()))
+}
+
+fn parse_term(word: &str) -> Result<SearchQuery, Error> {
+    let (field, value) = match word.find(':') {
+        None => (None, word),
+        Some(separator) => (
+            Some(word[..separator].to_lowercase()),
+            &word[separator + 1..],
+        ),
+    };
+
+    let value = value.replace('"', "").to_lowercase();
+
+    let query = match field.as_deref() {
+        Some("tag") => SearchQuery::Tag(value),
+        Some("url") => SearchQuery::Url(value),
+        Some("title") => SearchQuery::Title(value),
+        Some("description") => SearchQuery::Description(value),
+        Some("before") => SearchQuery::Before(parse_date(&value)?),
+        Some("after") => SearchQuery::After(parse_date(&value)?),
+        Some("has") => SearchQuery::Has(match value.as_ref() {
+            "cache" => HasTarget::Cache,
+            "description" => HasTarget::Description,
+            "tags" => HasTarget::Tags,
+            "url" => HasTarget::Url,
+            _ => bail!("Unknown has:{} search", value),
+        }),
+        Some("is") => SearchQuery::Is(match value.as_ref() {
+            "broken" => IsTarget::Broken,
+            "moved" => IsTarget::Moved,
+            _ => bail!("Unknown is:{} search", value),
+        }),
+        // Anything else, like urls with a scheme, is just text
+        _ => SearchQuery::Text(word.replace('"', "").to_lowercase()),
+    };
+
+    Ok(query)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<SearchQuery, Error> {
+        let mut alternatives = vec![self.parse_and()?];
+
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            alternatives.push(self.parse_and()?);
+        }
+
+        if alternatives.len() == 1 {
+            return Ok(alternatives.pop().unwrap());
+        }
+        Ok(SearchQuery::Or(alternatives))
+    }
+
+    fn parse_and(&mut self) -> Result<SearchQuery, Error> {
+        let mut terms = vec![];
+
+        loop {
+            match self.peek() {
+                None | Some(Token::Or) | Some(Token::CloseParen) => break,
+                _ => terms.push(self.parse_unary()?),
+            }
+        }
+
+        ensure!(!terms.is_empty(), "Expected a search term");
+
+        if terms.len() == 1 {
+            return Ok(terms.pop().unwrap());
+        }
+        Ok(SearchQuery::And(terms))
+    }
+
+    fn parse_unary(&mut self) -> Result<SearchQuery, Error> {
+        match self.next() {
+            Some(Token::Not) => Ok(SearchQuery::Not(Box::new(self.parse_unary()?))),
+            Some(Token::OpenParen) => {
+                let query = self.parse_or()?;
+                ensure!(
+                    self.next() == Some(Token::CloseParen),
+                    "Missing closing parenthesis in search query"
+                );
+                Ok(query)
+            }
+            Some(Token::Word(word)) => parse_term(&word),
+            _ => bail!("Expected a search term"),
+        }
+    }
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<SearchQuery, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(query)?,
+            position: 0,
+        };
+
+        if parser.peek().is_none() {
+            return Ok(SearchQuery::And(vec![]));
+        }
+
+        let query = parser.parse_or()?;
+        ensure!(
+            parser.peek().is_none(),
+            "Unexpected closing parenthesis in search query"
+        );
+
+        Ok(query)
+    }
+
+    /// Free text terms of the query, which are also looked for in cached pages. With
+    /// `include_negated` false, terms that must not appear are left out.
+    pub fn text_terms(&self, include_negated: bool) -> Vec<String> {
+        match self {
+            SearchQuery::And(queries) | SearchQuery::Or(queries) => queries
+                .iter()
+                .flat_map(|q| q.text_terms(include_negated))
+                .collect(),
+            SearchQuery::Not(query) if include_negated => query.text_terms(include_negated),
+            SearchQuery::Text(text) => vec![text.clone()],
+            _ => vec![],
+        }
+    }
+
+    /// Checks whether a pin matches the query. Free text also matches the pin's cached page.
+    pub fn matches<C: SearchContext>(&self, pin: &Pin, context: &C) -> bool {
+        let contains = |haystack: &str, needle: &str| haystack.to_lowercase().contains(needle);
+
+        match self {
+            SearchQuery::And(queries) => queries.iter().all(|q| q.matches(pin, context)),
+            SearchQuery::Or(queries) => queries.iter().any(|q| q.matches(pin, context)),
+            SearchQuery::Not(query) => !query.matches(pin, context),
+            SearchQuery::Text(text) => {
+                contains(&pin.title, text)
+                    || contains(&pin.description, text)
+                    || pin.urls.iter().any(|u| contains(u, text))
+                    || pin.tags.iter().any(|t| contains(t, text))
+                    || context.page_contains(pin, text)
+            }
+            SearchQuery::Tag(tag) => pin.tags.iter().any(|t| t.to_lowercase() == *tag),
+            SearchQuery::Url(url) => pin.urls.iter().any(|u| contains(u, url)),
+            SearchQuery::Title(title) => contains(&pin.title, title),
+            SearchQuery::Description(description) => contains(&pin.description, description),
+            SearchQuery::Before(date) => pin.created < *date,
+            SearchQuery::After(date) => pin.created >= *date,
+            SearchQuery::Has(HasTarget::Cache) => context.has_cache(pin),
+            SearchQuery::Has(HasTarget::Description) => !pin.description.trim().is_empty(),
+            SearchQuery::Has(HasTarget::Tags) => !pin.tags.is_empty(),
+            SearchQuery::Has(HasTarget::Url) => pin.urls.iter().any(|u| !u.is_empty()),
+            SearchQuery::Is(IsTarget::Broken) => context.link_status(pin).is_some_and(|s| s.broken),
+            SearchQuery::Is(IsTarget::Moved) => {
+                context.link_status(pin).is_some_and(|s| s.redirect_changed)
+            }
+        }
+    }
+}
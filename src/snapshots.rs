@@ -0,0 +1,431 @@
+use chrono::prelude::*;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::cache;
+use crate::pin_history::{self, DiffLine, LineChange};
+use crate::pins::Pin;
+use crate::user::UserInfo;
+
+/// Unchanged lines kept around each change when diffing snapshots
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Held while a timeline or the tag intervals are read, changed and written back, so two
+/// captures finishing at once don't lose one of them
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// A version of a pin's page. Captures with the same text as the previous one don't make a new
+/// snapshot, they only extend how long this one was seen for.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Hash of the page's text, which also names the snapshot's file
+    pub hash: String,
+    pub captured: DateTime<Utc>,
+    /// Latest capture that found the page unchanged
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Directory with the snapshots of a pin, named so it's moved to the trash along with the pin's
+/// other cached files
+pub fn snapshot_directory(username: &str, id: &str) -> PathBuf {
+    cache::cache_directory(username).join(format!("{}_snapshots", id))
+}
+
+fn timeline_filename(username: &str, id: &str) -> PathBuf {
+    snapshot_directory(username, id).join("timeline.json")
+}
+
+fn snapshot_filename(username: &str, id: &str, hash: &str) -> PathBuf {
+    snapshot_directory(username, id).join(format!("{}.html", hash))
+}
+
+/// Returns every snapshot of a pin, oldest first
+pub fn get_timeline(username: &str, id: &str) -> Result<Vec<Snapshot>, Error> {
+    let filename = timeline_filename(username, id);
+    if !filename.exists() {
+        return Ok(vec![]);
+    }
+
+    Ok(serde_json::from_str(&std::fs::read_to_string(filename)?)?)
+}
+
+/// Extracts the visible text of a page, with a line for every paragraph, heading, list item and
+/// such, which is what snapshots are compared by
+pub fn page_lines(html: &[u8]) -> String {
+    use html5ever::rcdom::{Handle, NodeData, RcDom};
+    use html5ever::tendril::TendrilSink;
+
+    const BLOCKS: &[&str] = &[
+        "p",
+        "div",
+        "section",
+        "article",
+        "header",
+        "footer",
+        "nav",
+        "aside",
+        "main",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "ul",
+        "ol",
+        "li",
+        "dl",
+        "dt",
+        "dd",
+        "table",
+        "tr",
+        "pre",
+        "blockquote",
+        "figcaption",
+        "br",
+        "hr",
+    ];
+
+    fn walk(node: &Handle, text: &mut String) {
+        let mut is_block = false;
+
+        match node.data {
+            NodeData::Text { ref contents } => text.push_str(&contents.borrow()),
+            NodeData::Element { ref name, .. } => {
+                let tag = name.local.to_ascii_lowercase();
+                if ["script", "style", "noscript", "template", "svg"].contains(&&*tag) {
+                    return;
+                }
+                is_block = BLOCKS.contains(&&*tag);
+            }
+            _ => {}
+        }
+
+        if is_block {
+            text.push('\n');
+        }
+        for child in node.children.borrow().iter() {
+            walk(child, text);
+        }
+        if is_block {
+            text.push('\n');
+        }
+    }
+
+    let dom = html5ever::parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .one(html);
+
+    let mut text = String::new();
+    walk(&dom.document, &mut text);
+
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Adds a freshly downloaded page to a pin's timeline. Pages with the same text as an earlier
+/// snapshot share its file.
+pub fn record_snapshot(username: &str, id: &str, html: &[u8]) -> Result<(), Error> {
+    let hash = sha1::Sha1::from(page_lines(html)).hexdigest();
+
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let now = Utc::now();
+    let mut timeline = get_timeline(username, id)?;
+
+    match timeline.last_mut() {
+        Some(latest) if latest.hash == hash => latest.last_seen = now,
+        _ => {
+            std::fs::create_dir_all(snapshot_directory(username, id))?;
+
+            let filename = snapshot_filename(username, id, &hash);
+            if !filename.exists() {
+                write_atomically(&filename, html)?;
+            }
+
+            timeline.push(Snapshot {
+                hash,
+                captured: now,
+                last_seen: now,
+            });
+        }
+    }
+
+    save_timeline(username, id, &timeline)
+}
+
+fn save_timeline(username: &str, id: &str, timeline: &[Snapshot]) -> Result<(), Error> {
+    write_atomically(
+        &timeline_filename(username, id),
+        serde_json::to_string(timeline)?.as_bytes(),
+    )
+}
+
+/// Writes a temporary file first and then renames it, so `filename` is never left half written
+fn write_atomically(filename: &Path, data: &[u8]) -> Result<(), Error> {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = match filename.parent() {
+        None => bail!("Invalid filename {:?}", filename),
+        Some(x) => x,
+    };
+
+    // The temporary file must be in the same directory for the rename to be atomic. Its name
+    // starts with a dot so it's never mistaken for a snapshot.
+    let mut temp_file = tempfile::Builder::new()
+        .prefix(".snapshot")
+        .permissions(std::fs::Permissions::from_mode(0o644))
+        .tempfile_in(directory)?;
+    temp_file.write_all(data)?;
+    temp_file.persist(filename)?;
+
+    Ok(())
+}
+
+/// Adds the snapshots of pin `from_id` to the timeline of `into_id`, like when pins are merged.
+/// Snapshots stay in the order they were captured, and the ones of `from_id` are left in place.
+pub fn merge_timelines(username: &str, into_id: &str, from_id: &str) -> Result<(), Error> {
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let from_timeline = get_timeline(username, from_id)?;
+    if from_timeline.is_empty() {
+        return Ok(());
+    }
+
+    let mut timeline = get_timeline(username, into_id)?;
+    std::fs::create_dir_all(snapshot_directory(username, into_id))?;
+
+    for snapshot in from_timeline {
+        let filename = snapshot_filename(username, into_id, &snapshot.hash);
+        if !filename.exists() {
+            std::fs::copy(
+                snapshot_filename(username, from_id, &snapshot.hash),
+                filename,
+            )?;
+        }
+        timeline.push(snapshot);
+    }
+
+    // Captures of the same text that end up next to each other count as one
+    timeline.sort_by_key(|s| s.captured);
+    timeline.dedup_by(|later, earlier| {
+        if later.hash != earlier.hash {
+            return false;
+        }
+        earlier.last_seen = std::cmp::max(earlier.last_seen, later.last_seen);
+        true
+    });
+
+    save_timeline(username, into_id, &timeline)
+}
+
+/// Line diff between the text of two snapshots. Long runs of unchanged lines are left out,
+/// keeping only a few lines around each change.
+pub fn diff_snapshots(
+    username: &str,
+    id: &str,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<Vec<Option<DiffLine>>, Error> {
+    let read_lines = |hash: &str| -> Result<String, Error> {
+        // Hashes come from the url, so they must not be able to point outside the directory
+        ensure!(
+            hash.chars().all(|c| c.is_ascii_hexdigit()),
+            "Invalid snapshot {}",
+            hash
+        );
+        Ok(page_lines(&std::fs::read(snapshot_filename(
+            username, id, hash,
+        ))?))
+    };
+
+    let lines = pin_history::diff_lines(&read_lines(old_hash)?, &read_lines(new_hash)?);
+
+    // Lines close enough to a change to be shown
+    let mut shown = vec![false; lines.len()];
+    for (i, line) in lines.iter().enumerate() {
+        if let LineChange::Unchanged = line.change {
+            continue;
+        }
+        let start = i.saturating_sub(DIFF_CONTEXT_LINES);
+        let end = std::cmp::min(i + DIFF_CONTEXT_LINES + 1, lines.len());
+        for is_shown in &mut shown[start..end] {
+            *is_shown = true;
+        }
+    }
+
+    // Every run of hidden lines is replaced by a single `None`
+    let mut result = vec![];
+    for (line, is_shown) in lines.into_iter().zip(shown) {
+        if is_shown {
+            result.push(Some(line));
+        } else if result
+            .last()
+            .is_none_or(|last: &Option<DiffLine>| last.is_some())
+        {
+            result.push(None);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Days between captures of a pin's page: its own setting, or the shortest one of its tags
+pub fn rearchive_interval(pin: &Pin, tag_intervals: &HashMap<String, u32>) -> Option<u32> {
+    pin.tags
+        .iter()
+        .filter_map(|tag| tag_intervals.get(tag).cloned())
+        .chain(pin.rearchive_days)
+        .filter(|days| *days > 0)
+        .min()
+}
+
+fn tag_intervals_filename(username: &str) -> PathBuf {
+    UserInfo::data_file(username, "rearchive_tags.json")
+}
+
+/// Days between captures for pins with each tag, as set by a user
+pub fn get_tag_intervals(username: &str) -> Result<HashMap<String, u32>, Error> {
+    let filename = tag_intervals_filename(username);
+    if !filename.exists() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(serde_json::from_str(&std::fs::read_to_string(filename)?)?)
+}
+
+/// Sets the days between captures for pins with `tag`. Setting it to 0 stops recapturing them.
+pub fn set_tag_interval(username: &str, tag: &str, days: u32) -> Result<(), Error> {
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let mut intervals = get_tag_intervals(username)?;
+    if days == 0 {
+        intervals.remove(tag);
+    } else {
+        intervals.insert(tag.to_string(), days);
+    }
+
+    let filename = tag_intervals_filename(username);
+    std::fs::create_dir_all(filename.parent().unwrap())?;
+    write_atomically(&filename, serde_json::to_string(&intervals)?.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page<S: AsRef<str>>(paragraphs: &[S]) -> Vec<u8> {
+        let body: String = paragraphs
+            .iter()
+            .map(|p| format!("<p>{}</p>\n", p.as_ref()))
+            .collect();
+        format!("<html><body>{}</body></html>", body).into_bytes()
+    }
+
+    /// A diff as `+line`, `-line`, ` line` and `...` for each gap
+    fn describe(diff: &[Option<DiffLine>]) -> Vec<String> {
+        diff.iter()
+            .map(|line| match line {
+                None => "...".to_string(),
+                Some(line) => {
+                    let sign = match line.change {
+                        LineChange::Added => '+',
+                        LineChange::Removed => '-',
+                        LineChange::Unchanged => ' ',
+                    };
+                    format!("{}{}", sign, line.text)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn page_lines_follow_blocks() {
+        let html = b"<html><head><title>Title</title><style>p { color: red }</style></head>
+            <body><h1>Heading</h1><p>Some <b>bold</b>   text</p><script>var x;</script>
+            <ul><li>One</li><li>Two</li></ul>Line<br>break</body></html>";
+
+        assert_eq!(
+            page_lines(html),
+            "Title\nHeading\nSome bold text\nOne\nTwo\nLine\nbreak"
+        );
+    }
+
+    #[test]
+    fn unchanged_captures_extend_the_latest_snapshot() {
+        crate::test_support::use_temp_directory();
+        let (username, id) = ("snapshot_dedup", "pin");
+
+        record_snapshot(username, id, &page(&["First", "version"])).unwrap();
+        // Only the text counts, not how the page is laid out
+        record_snapshot(
+            username,
+            id,
+            b"<html><body><div>First</div>  <p>version</p></body></html>",
+        )
+        .unwrap();
+        let timeline = get_timeline(username, id).unwrap();
+        assert_eq!(timeline.len(), 1);
+        assert!(timeline[0].last_seen > timeline[0].captured);
+
+        record_snapshot(username, id, &page(&["Second", "version"])).unwrap();
+        record_snapshot(username, id, &page(&["First", "version"])).unwrap();
+        let timeline = get_timeline(username, id).unwrap();
+        assert_eq!(timeline.len(), 3);
+        assert_eq!(timeline[0].hash, timeline[2].hash);
+
+        // Going back to an earlier text reuses its file
+        let files: Vec<_> = std::fs::read_dir(snapshot_directory(username, id))
+            .unwrap()
+            .map(|f| f.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(files.len(), 3, "{:?}", files);
+        assert!(files.iter().all(|f| !f.starts_with('.')));
+    }
+
+    #[test]
+    fn diffs_keep_context_around_changes() {
+        crate::test_support::use_temp_directory();
+        let (username, id) = ("snapshot_diff", "pin");
+
+        let old: Vec<String> = (1..=20).map(|i| format!("Line {}", i)).collect();
+        let mut new = old.clone();
+        new[9] = "Line ten".into();
+        new.remove(18);
+
+        record_snapshot(username, id, &page(&old)).unwrap();
+        record_snapshot(username, id, &page(&new)).unwrap();
+        let timeline = get_timeline(username, id).unwrap();
+
+        let diff = diff_snapshots(username, id, &timeline[0].hash, &timeline[1].hash).unwrap();
+        assert_eq!(
+            describe(&diff),
+            vec![
+                "...",
+                " Line 7",
+                " Line 8",
+                " Line 9",
+                "-Line 10",
+                "+Line ten",
+                " Line 11",
+                " Line 12",
+                " Line 13",
+                "...",
+                " Line 16",
+                " Line 17",
+                " Line 18",
+                "-Line 19",
+                " Line 20",
+            ]
+        );
+    }
+
+    #[test]
+    fn diffs_only_read_snapshot_files() {
+        assert!(diff_snapshots("snapshot_diff", "pin", "../../../etc/passwd", "abc").is_err());
+    }
+}
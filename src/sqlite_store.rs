@@ -0,0 +1,235 @@
+use chrono::prelude::*;
+use failure::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::Mutex;
+
+use crate::pins::{Pin, PinStore, TrashedPin};
+
+/// Stores pins as json blobs inside a SQLite database. The `pins` table holds the latest version
+/// of every pin, and `pin_revisions` all of them, including the latest. Trashing a pin moves it
+/// from `pins` to `trashed_pins`, leaving its revisions untouched.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(filename: &str) -> Result<SqliteStore, Error> {
+        let conn = Connection::open(filename)?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS pins (
+                username TEXT NOT NULL,
+                id TEXT NOT NULL,
+                created INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (username, id)
+            );
+            CREATE TABLE IF NOT EXISTS pin_revisions (
+                username TEXT NOT NULL,
+                id TEXT NOT NULL,
+                revision INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (username, id, revision)
+            );
+            CREATE TABLE IF NOT EXISTS trashed_pins (
+                username TEXT NOT NULL,
+                id TEXT NOT NULL,
+                deleted TEXT NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (username, id)
+            );
+            -- Pins saved before revisions were kept start their history with their current version
+            INSERT INTO pin_revisions (username, id, revision, data)
+                SELECT username, id, 0, data FROM pins AS p WHERE NOT EXISTS (
+                    SELECT 1 FROM pin_revisions AS r WHERE r.username = p.username AND r.id = p.id
+                );",
+        )?;
+
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl PinStore for SqliteStore {
+    fn get_pin(&self, username: &str, id: &str) -> Result<Pin, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let json_data: Option<String> = conn
+            .query_row(
+                "SELECT data FROM pins WHERE username = ?1 AND id = ?2",
+                params![username, id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match json_data {
+            None => bail!("Pin id {} does not exist", id),
+            Some(json_data) => Ok(serde_json::from_str(&json_data)?),
+        }
+    }
+
+    fn add_pin(&self, username: &str, pin: &Pin) -> Result<(), Error> {
+        self.add_pins(username, std::slice::from_ref(pin))
+    }
+
+    fn add_pins(&self, username: &str, pins: &[Pin]) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for pin in pins {
+            let pin_json = serde_json::to_string(pin).unwrap();
+
+            tx.execute(
+                "INSERT OR REPLACE INTO pins (username, id, created, data) VALUES (?1, ?2, ?3, ?4)",
+                params![username, pin.id, pin.created.timestamp_millis(), pin_json],
+            )?;
+            tx.execute(
+                "INSERT INTO pin_revisions (username, id, revision, data)
+                    SELECT ?1, ?2, COALESCE(MAX(revision) + 1, 0), ?3 FROM pin_revisions
+                    WHERE username = ?1 AND id = ?2",
+                params![username, pin.id, pin_json],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn get_all_pins(&self, username: &str) -> Result<Vec<Pin>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt =
+            conn.prepare("SELECT data FROM pins WHERE username = ?1 ORDER BY created DESC")?;
+        let rows = stmt.query_map(params![username], |row| row.get::<_, String>(0))?;
+
+        let mut pins = vec![];
+        for json_data in rows {
+            pins.push(serde_json::from_str(&json_data?)?);
+        }
+
+        Ok(pins)
+    }
+
+    fn get_pin_revisions(&self, username: &str, id: &str) -> Result<Vec<(u32, Pin)>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT revision, data FROM pin_revisions
+                WHERE username = ?1 AND id = ?2 ORDER BY revision",
+        )?;
+        let rows = stmt.query_map(params![username, id], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut revisions = vec![];
+        for row in rows {
+            let (revision, json_data) = row?;
+            revisions.push((revision, serde_json::from_str(&json_data)?));
+        }
+
+        Ok(revisions)
+    }
+
+    fn trash_pin(&self, username: &str, id: &str) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let moved = tx.execute(
+            "INSERT INTO trashed_pins (username, id, deleted, data)
+                SELECT username, id, ?3, data FROM pins
+                WHERE username = ?1 AND id = ?2",
+            params![
+                username,
+                id,
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+            ],
+        )?;
+        if moved == 0 {
+            bail!("Pin id {} does not exist", id);
+        }
+        tx.execute(
+            "DELETE FROM pins WHERE username = ?1 AND id = ?2",
+            params![username, id],
+        )?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn restore_pin(&self, username: &str, id: &str) -> Result<Pin, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let json_data: Option<String> = tx
+            .query_row(
+                "SELECT data FROM trashed_pins WHERE username = ?1 AND id = ?2",
+                params![username, id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let json_data = match json_data {
+            None => bail!("Pin id {} is not in the trash", id),
+            Some(x) => x,
+        };
+
+        let pin: Pin = serde_json::from_str(&json_data)?;
+        tx.execute(
+            "INSERT INTO pins (username, id, created, data) VALUES (?1, ?2, ?3, ?4)",
+            params![username, id, pin.created.timestamp_millis(), json_data],
+        )?;
+        tx.execute(
+            "DELETE FROM trashed_pins WHERE username = ?1 AND id = ?2",
+            params![username, id],
+        )?;
+
+        tx.commit()?;
+
+        Ok(pin)
+    }
+
+    fn get_trashed_pins(&self, username: &str) -> Result<Vec<TrashedPin>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT data, deleted FROM trashed_pins WHERE username = ?1 ORDER BY deleted DESC",
+        )?;
+        let rows = stmt.query_map(params![username], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut pins = vec![];
+        for row in rows {
+            let (json_data, deleted) = row?;
+            pins.push(TrashedPin {
+                pin: serde_json::from_str(&json_data)?,
+                deleted: DateTime::parse_from_rfc3339(&deleted)?.with_timezone(&Utc),
+            });
+        }
+
+        Ok(pins)
+    }
+
+    fn purge_pin(&self, username: &str, id: &str) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let deleted = tx.execute(
+            "DELETE FROM trashed_pins WHERE username = ?1 AND id = ?2",
+            params![username, id],
+        )?;
+        if deleted == 0 {
+            bail!("Pin id {} is not in the trash", id);
+        }
+        tx.execute(
+            "DELETE FROM pin_revisions WHERE username = ?1 AND id = ?2",
+            params![username, id],
+        )?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+}
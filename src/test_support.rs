@@ -0,0 +1,124 @@
+use failure::Error;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, Once};
+
+use crate::config::Config;
+use crate::http_client::{HttpClient, HttpResponse};
+use crate::page_renderer::RendererChoice;
+use crate::pins::BackingStore;
+
+/// Moves the tests into a scratch directory, as pins, caches and queues are all kept in
+/// directories relative to the current one. The working directory is shared by every test, so
+/// they all use the same scratch directory, and tell their data apart by username.
+pub fn use_temp_directory() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        // Background threads started by the tests may still write to it while the process exits,
+        // so it's left behind
+        let directory = tempfile::Builder::new()
+            .prefix("recense-test")
+            .tempdir()
+            .unwrap()
+            .keep();
+        std::env::set_current_dir(directory).unwrap();
+    });
+}
+
+/// Path of a file under the repository's `fixtures` directory
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(name)
+}
+
+/// Pin storage in the scratch directory, which downloads pages with the fake renderer and never
+/// checks links
+pub fn fake_storage() -> BackingStore {
+    use_temp_directory();
+    let config = Config {
+        renderer: RendererChoice::Fake,
+        renderer_fixtures: fixture("renderer").to_string_lossy().into_owned(),
+        link_check_interval_days: 0,
+        ..Default::default()
+    };
+    BackingStore::new(&config).unwrap()
+}
+
+/// Answers http requests with canned responses. Requests for anything it has no response for
+/// fail, like a site that can't be reached.
+#[derive(Default)]
+pub struct FakeHttpClient {
+    /// Responses by method and url
+    responses: Mutex<HashMap<(String, String), HttpResponse>>,
+    /// Method and url of every request made, in order
+    pub requests: Mutex<Vec<(String, String)>>,
+}
+
+impl FakeHttpClient {
+    pub fn new() -> FakeHttpClient {
+        FakeHttpClient::default()
+    }
+
+    /// Answers `method` requests for `url` with `response`, replacing any previous one
+    pub fn respond(&self, method: &str, url: &str, response: HttpResponse) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert((method.to_string(), url.to_string()), response);
+    }
+
+    fn call(&self, method: &str, url: &str) -> Result<HttpResponse, Error> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push((method.to_string(), url.to_string()));
+
+        match self
+            .responses
+            .lock()
+            .unwrap()
+            .get(&(method.to_string(), url.to_string()))
+        {
+            None => bail!("Could not get {}: connection refused", url),
+            Some(response) => Ok(response.clone()),
+        }
+    }
+}
+
+impl HttpClient for FakeHttpClient {
+    fn get(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error> {
+        let response = self.call("GET", url)?;
+        ensure!(
+            response.body.len() <= max_size,
+            "{} is bigger than {} bytes",
+            url,
+            max_size
+        );
+        Ok(response)
+    }
+
+    /// Canned responses are never redirects, unless they're made to look like one
+    fn get_single(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error> {
+        self.get(url, max_size)
+    }
+
+    fn head(&self, url: &str) -> Result<HttpResponse, Error> {
+        let mut response = self.call("HEAD", url)?;
+        response.body.clear();
+        Ok(response)
+    }
+}
+
+/// A response from `url` itself, without any redirect
+pub fn response(url: &str, status: u16, content_type: &str, body: &[u8]) -> HttpResponse {
+    HttpResponse {
+        url: url.to_string(),
+        http_version: "HTTP/1.1".into(),
+        status,
+        status_text: String::new(),
+        content_type: Some(content_type.to_string()).filter(|t| !t.is_empty()),
+        headers: vec![],
+        body: body.to_vec(),
+    }
+}
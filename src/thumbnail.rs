@@ -0,0 +1,163 @@
+use crate::errors::Context;
+use failure::Error;
+use std::path::Path;
+
+/// Size of the browser window screenshots are taken with
+pub const WINDOW_WIDTH: u32 = 1280;
+pub const WINDOW_HEIGHT: u32 = WINDOW_WIDTH / 2;
+
+/// How many times smaller than the screenshot thumbnails are
+const THUMB_RATIO: u32 = 5;
+/// Browsers draw a scrollbar along the right edge of screenshots, which gets cropped out
+const SCROLLBAR_WIDTH: u32 = 20;
+/// Icons are shown next to pin titles, which doesn't need them any bigger than this
+const FAVICON_SIZE: u32 = 32;
+
+/// Crops the scrollbar and anything below the first screenful off a screenshot, and scales it
+/// down to a thumbnail
+pub fn make_thumbnail(screenshot: &mut image::DynamicImage) -> image::RgbaImage {
+    let cropped_screenshot = image::imageops::crop(
+        screenshot,
+        0,
+        0,
+        WINDOW_WIDTH - SCROLLBAR_WIDTH,
+        WINDOW_HEIGHT,
+    );
+
+    image::imageops::thumbnail(
+        &cropped_screenshot,
+        (WINDOW_WIDTH - SCROLLBAR_WIDTH) / THUMB_RATIO,
+        WINDOW_HEIGHT / THUMB_RATIO,
+    )
+}
+
+/// Turns a screenshot file into a thumbnail at `destination`. The thumbnail is written to a
+/// temporary file first and then renamed, so `destination` is never left half written.
+pub fn save_thumbnail(screenshot_filename: &Path, destination: &Path) -> Result<(), Error> {
+    let mut screenshot = image::open(screenshot_filename)
+        .with_context(|| format!("Could not open screenshot {:?}", screenshot_filename))?;
+
+    let thumbnail = make_thumbnail(&mut screenshot);
+
+    save_atomically(&thumbnail, destination)
+}
+
+/// Scales a downloaded icon, in any format the image crate reads, down to a png at
+/// `destination`
+pub fn save_favicon(icon: &[u8], destination: &Path) -> Result<(), Error> {
+    let icon = image::load_from_memory(icon).with_context(|| "Could not read icon")?;
+
+    save_atomically(
+        &icon.thumbnail(FAVICON_SIZE, FAVICON_SIZE).to_rgba(),
+        destination,
+    )
+}
+
+/// Writes an image to a temporary file first and then renames it, so `destination` is never
+/// left half written. The format is picked from `destination`'s extension.
+fn save_atomically(image: &image::RgbaImage, destination: &Path) -> Result<(), Error> {
+    let directory = match destination.parent() {
+        None => bail!("Invalid image filename {:?}", destination),
+        Some(x) => x,
+    };
+    std::fs::create_dir_all(directory)?;
+
+    let extension = destination
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+
+    // The temporary file must be in the same directory for the rename to be atomic. Its name
+    // starts with a dot so it's never mistaken for a pin's cached file.
+    use std::os::unix::fs::PermissionsExt;
+    let temp_file = tempfile::Builder::new()
+        .prefix(".image")
+        .suffix(&format!(".{}", extension))
+        .permissions(std::fs::Permissions::from_mode(0o644))
+        .tempfile_in(directory)?;
+
+    image
+        .save(temp_file.path())
+        .with_context(|| format!("Could not save image {:?}", temp_file.path()))?;
+
+    temp_file.persist(destination)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+    use std::path::PathBuf;
+
+    /// A screenshot taller than the window: red where the thumbnail comes from, a blue scrollbar
+    /// along the right edge and green below the first screenful
+    fn fixture() -> PathBuf {
+        crate::test_support::fixture("thumbnail/screenshot.png")
+    }
+
+    #[test]
+    fn thumbnail_is_cropped_and_scaled_down() {
+        let mut screenshot = image::open(fixture()).unwrap();
+        let thumbnail = make_thumbnail(&mut screenshot);
+
+        assert_eq!(
+            thumbnail.dimensions(),
+            (
+                (WINDOW_WIDTH - SCROLLBAR_WIDTH) / THUMB_RATIO,
+                WINDOW_HEIGHT / THUMB_RATIO
+            )
+        );
+        for pixel in thumbnail.pixels() {
+            let [r, g, b, _] = pixel.data;
+            assert!(r > 150 && g < 50 && b < 50, "unexpected pixel {:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn thumbnail_is_saved_in_the_destination_format() {
+        let directory = tempfile::tempdir().unwrap();
+        let destination = directory.path().join("pin.jpg");
+
+        save_thumbnail(&fixture(), &destination).unwrap();
+
+        let data = std::fs::read(&destination).unwrap();
+        assert_eq!(
+            image::guess_format(&data).unwrap(),
+            image::ImageFormat::JPEG
+        );
+        assert_eq!(
+            image::load_from_memory(&data).unwrap().dimensions(),
+            (252, 128)
+        );
+    }
+
+    #[test]
+    fn existing_thumbnail_is_replaced_without_leftovers() {
+        let directory = tempfile::tempdir().unwrap();
+        let destination = directory.path().join("pin.jpg");
+        std::fs::write(&destination, b"old thumbnail").unwrap();
+
+        save_thumbnail(&fixture(), &destination).unwrap();
+
+        assert!(image::open(&destination).is_ok());
+        let files: Vec<_> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|f| f.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec![std::ffi::OsString::from("pin.jpg")]);
+    }
+
+    #[test]
+    fn failed_thumbnail_keeps_the_existing_one() {
+        let directory = tempfile::tempdir().unwrap();
+        let destination = directory.path().join("pin.jpg");
+        std::fs::write(&destination, b"old thumbnail").unwrap();
+
+        let missing = directory.path().join("missing.png");
+        assert!(save_thumbnail(&missing, &destination).is_err());
+
+        assert_eq!(std::fs::read(&destination).unwrap(), b"old thumbnail");
+    }
+}
@@ -0,0 +1,186 @@
+use url::{Position, Url};
+
+/// Query parameters that only tell where a visitor came from
+fn is_tracking_parameter(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("utm_") || name == "fbclid" || name == "gclid"
+}
+
+/// Rewrites an url the way it's saved in a pin: with a scheme, a lowercase host, no default
+/// port, no tracking parameters and no trailing slash. Urls that can't be parsed only get a
+/// scheme.
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim();
+    let lowercase = url.to_ascii_lowercase();
+    let url = if lowercase.starts_with("http://") || lowercase.starts_with("https://") {
+        url.to_string()
+    } else {
+        format!("http://{}", url)
+    };
+
+    // Parsing already lowercases the host and drops default ports
+    let mut parsed = match Url::parse(&url) {
+        Err(_) => return url,
+        Ok(x) => x,
+    };
+
+    // Parameters are filtered as they're written, as decoding them could change the ones kept
+    if let Some(query) = parsed.query().map(String::from) {
+        let kept = query
+            .split('&')
+            .filter(|p| !p.is_empty() && !is_tracking_parameter(p.split('=').next().unwrap()))
+            .collect::<Vec<_>>()
+            .join("&");
+        parsed.set_query(if kept.is_empty() { None } else { Some(&kept) });
+    }
+
+    // The root path is always written with its slash
+    let path = parsed.path().to_string();
+    if path.len() > 1 && path.ends_with('/') {
+        parsed.set_path(path.trim_end_matches('/'));
+    }
+
+    parsed.to_string()
+}
+
+/// What urls leading to the same page have in common, to find pins saved twice. On top of
+/// normalizing, it ignores the scheme, a leading `www.` and the fragment.
+pub fn duplicate_key(url: &str) -> String {
+    let normalized = normalize_url(url);
+    let mut parsed = match Url::parse(&normalized) {
+        Err(_) => return normalized,
+        Ok(x) => x,
+    };
+    parsed.set_fragment(None);
+
+    let host = parsed.host_str().unwrap_or_default();
+    format!(
+        "{}{}",
+        host.strip_prefix("www.").unwrap_or(host),
+        &parsed[Position::BeforePort..]
+    )
+}
+
+/// Whether a page's `<link rel=canonical>` can replace the url it was downloaded from. Some
+/// sites point every page at their home page, which would only lose where the pin led.
+pub fn is_usable_canonical(url: &str, canonical: &str) -> bool {
+    match (Url::parse(url), Url::parse(canonical)) {
+        (Ok(url), Ok(canonical)) => {
+            ["http", "https"].contains(&canonical.scheme())
+                && (canonical.path() != "/" || url.path() == "/")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracking_parameters_are_dropped() {
+        assert_eq!(
+            normalize_url("https://example.com/a?utm_source=x&id=3&UTM_Medium=y&fbclid=z"),
+            "https://example.com/a?id=3"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/a?gclid=1&utm_campaign=2"),
+            "https://example.com/a"
+        );
+        // Only whole parameter names count
+        assert_eq!(
+            normalize_url("https://example.com/a?not_utm_source=1&q=a%26b"),
+            "https://example.com/a?not_utm_source=1&q=a%26b"
+        );
+    }
+
+    #[test]
+    fn default_ports_and_host_case_are_dropped() {
+        assert_eq!(
+            normalize_url("http://Example.COM:80/Path"),
+            "http://example.com/Path"
+        );
+        assert_eq!(
+            normalize_url("https://example.com:443/"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            normalize_url("https://example.com:8443/"),
+            "https://example.com:8443/"
+        );
+    }
+
+    #[test]
+    fn trailing_slashes_are_dropped_except_for_the_root() {
+        assert_eq!(
+            normalize_url("https://example.com/a/b/"),
+            "https://example.com/a/b"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/a//"),
+            "https://example.com/a"
+        );
+        assert_eq!(normalize_url("https://example.com"), "https://example.com/");
+        assert_eq!(
+            normalize_url("https://example.com/?q=1"),
+            "https://example.com/?q=1"
+        );
+    }
+
+    #[test]
+    fn urls_without_scheme_get_one() {
+        assert_eq!(
+            normalize_url("  example.com/page "),
+            "http://example.com/page"
+        );
+        assert_eq!(
+            normalize_url("HTTPS://example.com/"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn fragments_are_kept() {
+        assert_eq!(
+            normalize_url("https://example.com/docs#install"),
+            "https://example.com/docs#install"
+        );
+    }
+
+    #[test]
+    fn duplicates_ignore_scheme_www_and_fragment() {
+        let key = duplicate_key("https://www.example.com/page/?utm_source=feed#top");
+        assert_eq!(key, "example.com/page");
+        assert_eq!(duplicate_key("http://example.com/page"), key);
+        assert_eq!(duplicate_key("example.com:80/page"), key);
+
+        assert_ne!(duplicate_key("https://example.com/other"), key);
+        assert_ne!(duplicate_key("https://example.com/page?id=2"), key);
+        assert_ne!(duplicate_key("https://example.com:8080/page"), key);
+        assert_ne!(duplicate_key("https://blog.example.com/page"), key);
+    }
+
+    #[test]
+    fn canonical_home_page_is_not_used() {
+        assert!(!is_usable_canonical(
+            "https://example.com/article/1",
+            "https://example.com/"
+        ));
+        assert!(is_usable_canonical(
+            "https://example.com/",
+            "https://example.com/"
+        ));
+        assert!(is_usable_canonical(
+            "https://example.com/article/1?ref=home",
+            "https://example.com/article/1"
+        ));
+        assert!(!is_usable_canonical(
+            "https://example.com/article/1",
+            "ftp://example.com/article/1"
+        ));
+        assert!(!is_usable_canonical(
+            "https://example.com/article/1",
+            "/article/1"
+        ));
+    }
+}
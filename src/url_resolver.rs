@@ -0,0 +1,219 @@
+use failure::Error;
+use html5ever::rcdom::{Handle, NodeData};
+use html5ever::Attribute;
+use url::Url;
+
+use crate::page_inliner::{format_srcset, parse_srcset, replace_css_urls};
+
+/// Attributes holding a single url, whatever element they're on
+pub const URL_ATTRIBUTES: &[&str] = &[
+    "href",
+    "src",
+    "action",
+    "formaction",
+    "poster",
+    "cite",
+    "background",
+    "longdesc",
+    "manifest",
+    "xlink:href",
+];
+
+/// Resolves a url found in a page against the page's base url, as described in RFC 3986.
+/// Returns `None` for references that should be left as they are, like links to a fragment of
+/// the page itself.
+pub fn resolve_url(base: &Url, reference: &str) -> Option<String> {
+    let trimmed = reference.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return None;
+    }
+
+    let lowercase = trimmed.to_lowercase();
+    if lowercase.starts_with("data:") || lowercase.starts_with("javascript:") {
+        return None;
+    }
+
+    base.join(trimmed).ok().map(String::from)
+}
+
+/// Finds the url all relative urls in a page are relative to, which is the page's own url
+/// unless it has a `<base href>`
+fn find_base_url(node: &Handle, document_url: &Url) -> Option<Url> {
+    if let NodeData::Element {
+        ref name,
+        ref attrs,
+        ..
+    } = node.data
+    {
+        if name.local.eq_str_ignore_ascii_case("base") {
+            let href = attrs
+                .borrow()
+                .iter()
+                .find(|a| a.name.local.eq_str_ignore_ascii_case("href"))
+                .map(|a| a.value.to_string());
+            if let Some(href) = href {
+                return document_url.join(href.trim()).ok();
+            }
+        }
+    }
+
+    node.children
+        .borrow()
+        .iter()
+        .find_map(|child| find_base_url(child, document_url))
+}
+
+fn resolve_css_urls(css: &str, base: &Url) -> String {
+    replace_css_urls(css, |reference| resolve_url(base, reference))
+}
+
+/// Lowercase name of an attribute as written in the page. Namespaced attributes like xlink:href
+/// are split into prefix and local name by the parser, and joined back here.
+pub fn attribute_name(attr: &Attribute) -> String {
+    match attr.name.prefix {
+        Some(ref prefix) => format!("{}:{}", prefix, attr.name.local),
+        None => attr.name.local.to_lowercase(),
+    }
+}
+
+fn resolve_node_urls(node: &Handle, base: &Url) {
+    if let NodeData::Element {
+        ref name,
+        ref attrs,
+        ..
+    } = node.data
+    {
+        for attr in attrs.borrow_mut().iter_mut() {
+            let attr_name = attribute_name(attr);
+
+            let is_url = URL_ATTRIBUTES.contains(&attr_name.as_str())
+                || (attr_name == "data" && name.local.eq_str_ignore_ascii_case("object"));
+
+            if is_url {
+                if let Some(resolved) = resolve_url(base, &attr.value) {
+                    attr.value = resolved.into();
+                }
+            } else if attr_name == "srcset" {
+                let mut candidates = parse_srcset(&attr.value);
+                for candidate in candidates.iter_mut() {
+                    if let Some(resolved) = resolve_url(base, &candidate.url) {
+                        candidate.url = resolved;
+                    }
+                }
+                attr.value = format_srcset(&candidates).into();
+            } else if attr_name == "ping" {
+                let resolved: Vec<String> = attr
+                    .value
+                    .split_whitespace()
+                    .map(|u| resolve_url(base, u).unwrap_or_else(|| u.to_string()))
+                    .collect();
+                attr.value = resolved.join(" ").into();
+            } else if attr_name == "style" {
+                attr.value = resolve_css_urls(&attr.value, base).into();
+            }
+        }
+
+        if name.local.eq_str_ignore_ascii_case("style") {
+            for child in node.children.borrow().iter() {
+                if let NodeData::Text { ref contents } = child.data {
+                    let css = resolve_css_urls(&contents.borrow(), base);
+                    *contents.borrow_mut() = css.into();
+                }
+            }
+        }
+    }
+
+    for child in node.children.borrow().iter() {
+        resolve_node_urls(child, base);
+    }
+}
+
+/// Makes every url in a page absolute, so the page still points to the right places when it's
+/// served from somewhere else. `document_url` should be the url the page was finally loaded
+/// from, after any redirects.
+pub fn resolve_document_urls(document: &Handle, document_url: &str) -> Result<(), Error> {
+    let document_url = match Url::parse(document_url) {
+        Err(err) => bail!("Can't resolve urls against {}: {}", document_url, err),
+        Ok(x) => x,
+    };
+
+    let base = find_base_url(document, &document_url).unwrap_or(document_url);
+    resolve_node_urls(document, &base);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fixture;
+    use html5ever::tendril::TendrilSink;
+
+    /// Resolves the urls of `fixtures/url_resolver/{name}.html` as if it was loaded from `url`,
+    /// and compares the result with `{name}.expected.html`
+    fn check_fixture(name: &str, url: &str) {
+        let html = std::fs::read(fixture(&format!("url_resolver/{}.html", name))).unwrap();
+        let dom = html5ever::parse_document(html5ever::rcdom::RcDom::default(), Default::default())
+            .from_utf8()
+            .one(&html[..]);
+
+        resolve_document_urls(&dom.document, url).unwrap();
+
+        let mut out = vec![];
+        html5ever::serialize(&mut out, &dom.document, Default::default()).unwrap();
+        let expected =
+            std::fs::read_to_string(fixture(&format!("url_resolver/{}.expected.html", name)))
+                .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected.trim_end());
+    }
+
+    #[test]
+    fn page_urls_are_resolved() {
+        check_fixture("page", "https://example.com/blog/posts/today.html");
+    }
+
+    #[test]
+    fn base_href_is_used() {
+        check_fixture("base", "http://example.com/blog/posts/today.html");
+    }
+
+    #[test]
+    fn urls_are_resolved() {
+        let base = Url::parse("https://example.com/a/b/page.html?x=1").unwrap();
+        let resolve = |reference| resolve_url(&base, reference);
+
+        assert_eq!(resolve("c.png").unwrap(), "https://example.com/a/b/c.png");
+        assert_eq!(resolve("../c.png").unwrap(), "https://example.com/a/c.png");
+        assert_eq!(
+            resolve("../../../../c.png").unwrap(),
+            "https://example.com/c.png"
+        );
+        assert_eq!(resolve("  /c.png ").unwrap(), "https://example.com/c.png");
+        assert_eq!(
+            resolve("//cdn.example.net/c.png").unwrap(),
+            "https://cdn.example.net/c.png"
+        );
+        assert_eq!(
+            resolve("?y=2").unwrap(),
+            "https://example.com/a/b/page.html?y=2"
+        );
+        assert_eq!(
+            resolve("mailto:someone@example.com").unwrap(),
+            "mailto:someone@example.com"
+        );
+    }
+
+    #[test]
+    fn page_references_are_skipped() {
+        let base = Url::parse("https://example.com/page.html").unwrap();
+        for reference in &[
+            "",
+            "  ",
+            "#top",
+            "data:text/plain,hi",
+            " JavaScript:alert(1)",
+        ] {
+            assert_eq!(resolve_url(&base, reference), None, "{:?}", reference);
+        }
+    }
+}
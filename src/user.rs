@@ -14,7 +14,7 @@ impl UserInfo {
     pub fn new_user(username: String, email: String, password: String) -> Result<(), Error> {
         let mut password_salt = vec![0u8; 64];
         use chrono::prelude::*;
-        let timestamp = Utc::now().timestamp_nanos();
+        let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or_default();
 
         rand_pcg::Mcg128Xsl64::new(0xcafe_f00d_d15e_a5e5 + timestamp as u128)
             .fill_bytes(&mut password_salt);
@@ -47,7 +47,7 @@ impl UserInfo {
         // TODO - Perform username String validation
         // TODO - Check username doesn't exist yet
 
-        std::fs::create_dir_all(&UserInfo::user_dir(&username))?;
+        std::fs::create_dir_all(UserInfo::user_dir(&username))?;
 
         let userinfo = UserInfo {
             username: username.clone(),
@@ -73,8 +73,27 @@ impl UserInfo {
         UserInfo::user_dir(username).join("userinfo.json")
     }
 
+    /// File in the user's directory to keep some of their data in
+    pub fn data_file(username: &str, filename: &str) -> std::path::PathBuf {
+        UserInfo::user_dir(username).join(filename)
+    }
+
+    pub fn all_usernames() -> Result<Vec<String>, Error> {
+        let users_dir = std::path::Path::new("users");
+
+        if !users_dir.exists() {
+            return Ok(vec![]);
+        }
+
+        Ok(std::fs::read_dir(users_dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(String::from))
+            .collect())
+    }
+
     pub fn load_user_data(username: &str) -> Result<UserInfo, Error> {
-        let json_data = std::fs::read_to_string(&UserInfo::user_file(username))?;
+        let json_data = std::fs::read_to_string(UserInfo::user_file(username))?;
         Ok(serde_json::from_str(&json_data)?)
     }
 
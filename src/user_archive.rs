@@ -1,49 +1,762 @@
-use crate::pins::BackingStore;
+use crate::cache;
+use crate::pin_redirects;
+use crate::pins::{BackingStore, Pin};
+use crate::snapshots::{self, Snapshot};
+use crate::user::UserInfo;
+use bytes::Bytes;
+use chrono::prelude::*;
 use failure::Error;
-use std::io::Write;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-pub fn generate_archive_for_user(username: String) -> Result<Vec<u8>, Error> {
-    let dir_str = BackingStore::pin_directory(&username);
-    let dir_path = Path::new(&dir_str);
+/// Directory of the archive everything is in
+const ARCHIVE_DIRECTORY: &str = "recense_user_archive";
 
-    if !dir_path.exists() {
+/// Version of the archive layout, written in its manifest. Archives of version 1 only hold the
+/// latest revision of each pin, as `{id}_v0.json`.
+pub const ARCHIVE_VERSION: u32 = 2;
+
+/// Biggest file restored from an archive, however small it is compressed. Each file is read into
+/// memory to be checked, so a tiny zip that unpacks to gigabytes can't use it all up.
+const MAX_ENTRY_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Chunks of an archive waiting to be sent, before writing it stops to wait for the download
+const STREAMED_CHUNKS: usize = 4;
+
+/// Kinds of cached files restored along with a pin
+const CACHED_EXTENSIONS: &[&str] = &["html", "jpg", "warc", "favicon.png"];
+
+/// Settings files of a user that are archived, as they're kept in the user's directory
+const SETTINGS_FILES: &[&str] = &["rearchive_tags.json", "pin_redirects.json"];
+
+/// Size and checksum of a file in an archive
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub sha1: String,
+}
+
+/// Description of an archive, written as `manifest.json` next to the files it lists
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    pub pins: usize,
+    pub cached_files: usize,
+    /// Every other file in the archive, by its path inside the archive's directory
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+/// Where an archive is written to, sending it through a channel while it's written rather than
+/// keeping it whole. The zip writer goes back to fill in the size and checksum of each file once
+/// the file is written, then seeks back to the end, so everything before the end is sent then.
+struct ChannelOutput {
+    sender: Option<mpsc::Sender<Result<Bytes, Error>>>,
+    /// Bytes not sent yet, which start at `sent` in the archive
+    held: Vec<u8>,
+    sent: u64,
+    position: u64,
+}
+
+impl ChannelOutput {
+    fn new(sender: mpsc::Sender<Result<Bytes, Error>>) -> ChannelOutput {
+        ChannelOutput {
+            sender: Some(sender),
+            held: vec![],
+            sent: 0,
+            position: 0,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.sent + self.held.len() as u64
+    }
+
+    /// Sends everything written so far, waiting while the download is behind
+    fn send_held(&mut self) -> std::io::Result<()> {
+        if self.held.is_empty() {
+            return Ok(());
+        }
+
+        let cancelled = || {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "The archive's download was cancelled",
+            )
+        };
+        let sender = self.sender.take().ok_or_else(cancelled)?;
+        let chunk = Bytes::from(std::mem::take(&mut self.held));
+        self.sent += chunk.len() as u64;
+        self.sender = Some(sender.send(Ok(chunk)).wait().map_err(|_| cancelled())?);
+        Ok(())
+    }
+}
+
+impl Write for ChannelOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let offset = (self.position - self.sent) as usize;
+        let overwritten = std::cmp::min(buf.len(), self.held.len() - offset);
+        self.held[offset..offset + overwritten].copy_from_slice(&buf[..overwritten]);
+        self.held.extend_from_slice(&buf[overwritten..]);
+
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for ChannelOutput {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let end = self.end();
+        let position = match pos {
+            SeekFrom::Start(x) => x as i64,
+            SeekFrom::Current(x) => self.position as i64 + x,
+            SeekFrom::End(x) => end as i64 + x,
+        };
+        if position < self.sent as i64 || position > end as i64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Can't seek to a part of the archive that was already sent, or isn't written yet",
+            ));
+        }
+
+        let is_back_to_end = self.position < end && position as u64 == end;
+        self.position = position as u64;
+        if is_back_to_end {
+            self.send_held()?;
+        }
+        Ok(self.position)
+    }
+}
+
+/// Writes files into an archive, listing each one in its manifest
+struct ArchiveWriter<W: Write + Seek> {
+    zip: zip::ZipWriter<W>,
+    manifest: Manifest,
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    fn add_file(&mut self, path: &str, data: &[u8]) -> Result<(), Error> {
+        self.zip.start_file(
+            format!("{}/{}", ARCHIVE_DIRECTORY, path),
+            zip::write::FileOptions::default(),
+        )?;
+        self.zip.write_all(data)?;
+
+        self.manifest.files.insert(
+            path.to_string(),
+            ManifestEntry {
+                size: data.len() as u64,
+                sha1: sha1::Sha1::from(data).hexdigest(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Adds a file of the cache, if it's there
+    fn add_cached_file(&mut self, path: &str, filename: &Path) -> Result<(), Error> {
+        if filename.is_file() {
+            self.add_file(path, &std::fs::read(filename)?)?;
+            self.manifest.cached_files += 1;
+        }
+        Ok(())
+    }
+
+    /// Adds the snapshots of a pin, leaving out files with names that can't be restored
+    fn add_snapshots(&mut self, username: &str, id: &str) -> Result<(), Error> {
+        let directory = snapshots::snapshot_directory(username, id);
+        if !directory.is_dir() {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            match path.file_name().and_then(|f| f.to_str()) {
+                None => warn!(
+                    "Not archiving {}, its name isn't valid UTF-8",
+                    path.display()
+                ),
+                Some(filename) => {
+                    self.add_cached_file(&format!("{}_snapshots/{}", id, filename), &path)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the manifest and finishes the archive
+    fn finish(mut self) -> Result<W, Error> {
+        let manifest = serde_json::to_string_pretty(&self.manifest)?;
+        self.zip.start_file(
+            format!("{}/manifest.json", ARCHIVE_DIRECTORY),
+            zip::write::FileOptions::default(),
+        )?;
+        self.zip.write_all(manifest.as_bytes())?;
+
+        Ok(self.zip.finish()?)
+    }
+}
+
+/// Writes every pin of a user into a zip file, along with all their revisions, their WARC files
+/// and settings, and with `include_cache` their cached pages, thumbnails, icons and snapshots
+/// too. The archive is written on a thread of its own and sent a chunk at a time, as cached pages
+/// can add up to a lot. An error while it's written ends the stream with that error.
+pub fn generate_archive_for_user(
+    storage: &BackingStore,
+    username: &str,
+    include_cache: bool,
+) -> Result<impl Stream<Item = Bytes, Error = Error>, Error> {
+    let pins = storage.get_all_pins(username)?;
+
+    if pins.is_empty() {
         bail!("No data to archive for user {}", username);
     }
 
-    let mut buf: Vec<u8> = vec![];
-    {
-        let w = std::io::Cursor::new(&mut buf);
-        let mut zip = zip::ZipWriter::new(w);
+    let (sender, receiver) = mpsc::channel(STREAMED_CHUNKS);
+    let storage = storage.clone();
+    let username = username.to_string();
+    std::thread::spawn(move || {
+        let output = ChannelOutput::new(sender.clone());
+        let result = write_archive(&storage, &username, &pins, include_cache, output)
+            .and_then(|mut output| Ok(output.send_held()?));
+        if let Err(err) = result {
+            error!("Error writing the archive of {}: {:?}", username, err);
+            let _ = sender.send(Err(err)).wait();
+        }
+    });
 
-        let file_options = zip::write::FileOptions::default();
+    Ok(receiver.then(|chunk| match chunk {
+        Err(()) => Err(format_err!("The archive stopped being written")),
+        Ok(chunk) => chunk,
+    }))
+}
 
-        zip.add_directory("recense_user_archive", file_options)?;
+fn write_archive<W: Write + Seek>(
+    storage: &BackingStore,
+    username: &str,
+    pins: &[Pin],
+    include_cache: bool,
+    output: W,
+) -> Result<W, Error> {
+    let mut archive = ArchiveWriter {
+        zip: zip::ZipWriter::new(output),
+        manifest: Manifest {
+            version: ARCHIVE_VERSION,
+            created: Utc::now(),
+            pins: 0,
+            cached_files: 0,
+            files: BTreeMap::new(),
+        },
+    };
+    archive
+        .zip
+        .add_directory(ARCHIVE_DIRECTORY, zip::write::FileOptions::default())?;
 
-        for file in std::fs::read_dir(dir_path)? {
-            if file.is_err() {
-                continue;
+    let cache_directory = cache::cache_directory(username);
+    for pin in pins {
+        // The pin may have been deleted since the list was read
+        let revisions = storage.get_pin_revisions(username, &pin.id)?;
+        if revisions.is_empty() {
+            continue;
+        }
+        for (number, revision) in &revisions {
+            archive.add_file(
+                &format!("{}_v{}.json", pin.id, number),
+                serde_json::to_string(revision)?.as_bytes(),
+            )?;
+        }
+        archive.manifest.pins += 1;
+
+        let extensions: &[&str] = if include_cache {
+            &["warc", "html", "jpg"]
+        } else {
+            &["warc"]
+        };
+        for url_index in 0..pin.urls.len() {
+            for extension in extensions {
+                let filename = format!("{}.{}", cache::url_basename(&pin.id, url_index), extension);
+                archive.add_cached_file(&filename, &cache_directory.join(&filename))?;
+            }
+        }
+
+        if include_cache {
+            archive.add_cached_file(
+                &format!("{}.favicon.png", pin.id),
+                &cache::favicon_filename(username, &pin.id),
+            )?;
+            archive.add_snapshots(username, &pin.id)?;
+        }
+    }
+
+    // The password hash stays on the server it was made on
+    let userinfo = UserInfo::load_user_data(username)?;
+    archive.add_file(
+        "settings/userinfo.json",
+        serde_json::to_string(&json!({
+            "username": userinfo.username,
+            "email": userinfo.email,
+        }))?
+        .as_bytes(),
+    )?;
+    for filename in SETTINGS_FILES {
+        let path = UserInfo::data_file(username, filename);
+        if path.is_file() {
+            archive.add_file(&format!("settings/{}", filename), &std::fs::read(path)?)?;
+        }
+    }
+
+    archive.finish()
+}
+
+/// What to do with the pins a user already has when restoring an archive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestoreMode {
+    /// Keep them, adding the archived pins next to them
+    Merge,
+    /// Make the account just like the archive, moving pins it doesn't have to the trash
+    Replace,
+}
+
+/// What restoring an archive did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Pins already saved just like they are in the archive
+    pub unchanged: usize,
+    /// Pins whose id was taken by a different pin, restored under a new id
+    pub renamed: usize,
+    /// Pins missing from the archive, moved to the trash when replacing
+    pub trashed: usize,
+    pub cached_files: usize,
+}
+
+/// A file of an archive that can be restored
+enum ArchiveEntry {
+    /// A revision of a pin, by its id and revision number
+    Pin(String, u32),
+    /// A cached file of one of a pin's urls, by its pin, url index and extension
+    CachedFile(String, usize, String),
+    /// A file in a pin's snapshot directory, by its pin and filename
+    SnapshotFile(String, String),
+    /// One of the user's settings files, by its filename
+    Settings(String),
+}
+
+/// Ids name files, so only the letters and digits of the hashes they're made of are allowed
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn parse_entry(name: &str) -> Option<ArchiveEntry> {
+    let path = name.strip_prefix(ARCHIVE_DIRECTORY)?.strip_prefix('/')?;
+
+    match path.split('/').collect::<Vec<_>>()[..] {
+        [filename] => {
+            if let Some(stem) = filename.strip_suffix(".json") {
+                let separator = stem.rfind("_v")?;
+                let revision = stem[separator + 2..].parse().ok()?;
+                return Some(ArchiveEntry::Pin(stem[..separator].to_string(), revision));
+            }
+
+            let (id, index, extension) = cache::parse_cached_filename(filename)?;
+            if is_valid_id(id) && CACHED_EXTENSIONS.contains(&extension) {
+                Some(ArchiveEntry::CachedFile(
+                    id.to_string(),
+                    index,
+                    extension.to_string(),
+                ))
+            } else {
+                None
             }
-            let file = file.as_ref().unwrap();
-            let path = file.path();
-            let filename_opt = path.as_path().file_name();
+        }
+        ["settings", filename] => Some(ArchiveEntry::Settings(filename.to_string())),
+        [directory, filename] => {
+            let id = directory.strip_suffix("_snapshots")?;
+            let is_snapshot = filename == "timeline.json"
+                || filename.strip_suffix(".html").is_some_and(|hash| {
+                    !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+                });
+
+            if is_valid_id(id) && is_snapshot {
+                Some(ArchiveEntry::SnapshotFile(
+                    id.to_string(),
+                    filename.to_string(),
+                ))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Reads a file of an archive, refusing it once it gets bigger than `MAX_ENTRY_SIZE`, whatever
+/// size the zip says it has
+fn read_entry(file: &mut zip::read::ZipFile) -> Result<Vec<u8>, Error> {
+    ensure!(
+        file.size() <= MAX_ENTRY_SIZE,
+        "{} is too big to be restored",
+        file.name()
+    );
+
+    let mut data = vec![];
+    file.take(MAX_ENTRY_SIZE + 1).read_to_end(&mut data)?;
+    ensure!(
+        data.len() as u64 <= MAX_ENTRY_SIZE,
+        "{} is too big to be restored",
+        file.name()
+    );
+    Ok(data)
+}
+
+/// Whether two pins hold the same bookmark, whatever their dates
+fn same_content(a: &Pin, b: &Pin) -> bool {
+    a.title == b.title && a.urls == b.urls && a.description == b.description && a.tags == b.tags
+}
+
+/// An id for a restored pin whose own id is taken
+fn fresh_id(id: &str) -> String {
+    let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    sha1::Sha1::from(format!("{}{}", id, now)).hexdigest()
+}
+
+/// Restores the pins in an archive made by `generate_archive_for_user` with all their revisions,
+/// along with the cached files, the capture intervals of tags and the redirects of merged pins it
+/// holds. Every pin, and every file listed in the
+/// manifest, is checked before anything is saved, so an archive that's damaged or wasn't made by
+/// recense changes nothing. The account's email and password stay as they are.
+pub fn restore_archive(
+    storage: &BackingStore,
+    username: &str,
+    data: &[u8],
+    mode: RestoreMode,
+) -> Result<RestoreSummary, Error> {
+    let mut zip = match zip::ZipArchive::new(std::io::Cursor::new(data)) {
+        Err(err) => bail!("Not a zip file: {}", err),
+        Ok(x) => x,
+    };
+
+    // Archives made before they had a manifest can't be checked, but can still be restored
+    let manifest_name = format!("{}/manifest.json", ARCHIVE_DIRECTORY);
+    let manifest: Option<Manifest> = match zip.by_name(&manifest_name) {
+        Err(_) => None,
+        Ok(mut file) => {
+            let json = read_entry(&mut file)?;
+            match serde_json::from_slice(&json) {
+                Err(err) => bail!("The archive's manifest is not valid: {}", err),
+                Ok(x) => Some(x),
+            }
+        }
+    };
+    if let Some(manifest) = &manifest {
+        ensure!(
+            manifest.version <= ARCHIVE_VERSION,
+            "The archive was made by a newer version of recense"
+        );
+    }
+
+    // Revisions of every pin, by the pin's id, with pins in the order they're archived in
+    let mut archived_ids: Vec<String> = vec![];
+    let mut archived_revisions: HashMap<String, BTreeMap<u32, Pin>> = HashMap::new();
+    let mut tag_intervals: Option<HashMap<String, u32>> = None;
+    let mut redirects: Option<HashMap<String, String>> = None;
+    let mut checked_files = 0;
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let name = file.name().to_string();
+        if file.is_dir() || name == manifest_name {
+            continue;
+        }
+
+        let data = read_entry(&mut file)?;
+
+        if let Some(manifest) = &manifest {
+            let path = name
+                .strip_prefix(ARCHIVE_DIRECTORY)
+                .and_then(|p| p.strip_prefix('/'))
+                .unwrap_or(&name);
+            match manifest.files.get(path) {
+                None => bail!("{} is not listed in the archive's manifest", name),
+                Some(entry) => ensure!(
+                    entry.size == data.len() as u64
+                        && entry.sha1 == sha1::Sha1::from(&data).hexdigest(),
+                    "{} is damaged, it doesn't match the archive's manifest",
+                    name
+                ),
+            }
+            checked_files += 1;
+        }
+
+        match parse_entry(&name) {
+            Some(ArchiveEntry::Pin(id, revision)) => {
+                let pin: Pin = match serde_json::from_slice(&data) {
+                    Err(err) => bail!("{} is not a valid pin: {}", name, err),
+                    Ok(x) => x,
+                };
+
+                ensure!(
+                    is_valid_id(&pin.id) && pin.id == id,
+                    "{} holds a pin with the id {}",
+                    name,
+                    pin.id
+                );
+                if !archived_revisions.contains_key(&id) {
+                    archived_ids.push(id.clone());
+                }
+                ensure!(
+                    archived_revisions
+                        .entry(id)
+                        .or_default()
+                        .insert(revision, pin)
+                        .is_none(),
+                    "{} is in the archive twice",
+                    name
+                );
+            }
+            Some(ArchiveEntry::SnapshotFile(_, filename)) if filename == "timeline.json" => {
+                if let Err(err) = serde_json::from_slice::<Vec<Snapshot>>(&data) {
+                    bail!("{} is not a valid snapshot timeline: {}", name, err);
+                }
+            }
+            Some(ArchiveEntry::Settings(filename)) if filename == "rearchive_tags.json" => {
+                match serde_json::from_slice(&data) {
+                    Err(err) => bail!("{} is not valid: {}", name, err),
+                    Ok(x) => tag_intervals = Some(x),
+                }
+            }
+            Some(ArchiveEntry::Settings(filename)) if filename == "pin_redirects.json" => {
+                match serde_json::from_slice(&data) {
+                    Err(err) => bail!("{} is not valid: {}", name, err),
+                    Ok(x) => redirects = Some(x),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(manifest) = &manifest {
+        ensure!(
+            checked_files == manifest.files.len(),
+            "Some files listed in the archive's manifest are missing"
+        );
+        ensure!(
+            archived_ids.len() == manifest.pins,
+            "The archive should hold {} pins, but has {}",
+            manifest.pins,
+            archived_ids.len()
+        );
+    }
+    ensure!(!archived_ids.is_empty(), "No pins found in the archive");
+
+    let existing: HashMap<String, Pin> = storage
+        .get_all_pins(username)?
+        .into_iter()
+        .map(|pin| (pin.id.clone(), pin))
+        .collect();
+    let trashed: HashSet<String> = storage
+        .get_trashed_pins(username)?
+        .into_iter()
+        .map(|trashed_pin| trashed_pin.pin.id)
+        .collect();
+
+    let mut summary = RestoreSummary::default();
 
-            if filename_opt.is_none() {
+    // Id each restored pin is saved under, by its id in the archive
+    let mut restored_ids: HashMap<String, String> = HashMap::new();
+    let mut kept_ids: HashSet<String> = HashSet::new();
+    let mut pins = vec![];
+    for id in archived_ids {
+        // The latest revision is the pin, the ones before it its history
+        let mut revisions: Vec<Pin> = archived_revisions
+            .remove(&id)
+            .unwrap_or_default()
+            .into_values()
+            .collect();
+        let mut pin = match revisions.pop() {
+            None => continue,
+            Some(x) => x,
+        };
+
+        let is_taken = match existing.get(&pin.id) {
+            Some(current) if same_content(current, &pin) => {
+                summary.unchanged += 1;
+                kept_ids.insert(pin.id);
                 continue;
             }
+            // Replacing saves the archived pin over the current one, which is kept as a revision
+            Some(_) => mode == RestoreMode::Merge,
+            None => trashed.contains(&pin.id),
+        };
+
+        let id = if is_taken {
+            summary.renamed += 1;
+            fresh_id(&pin.id)
+        } else {
+            pin.id.clone()
+        };
+        // A pin saved over the current one keeps the current one's history rather than the
+        // archived one, as they most likely share their beginning
+        if existing.contains_key(&pin.id) && !is_taken {
+            revisions.clear();
+        }
 
-            let filename = filename_opt.unwrap().to_str().unwrap().to_string();
+        restored_ids.insert(pin.id.clone(), id.clone());
+        kept_ids.insert(id.clone());
+        for revision in &mut revisions {
+            revision.id = id.clone();
+        }
+        pin.id = id;
+        pins.push((revisions, pin));
+    }
 
-            let bytes = std::fs::read(&Path::new(&format!(
-                "{}/{}",
-                dir_path.to_str().unwrap(),
-                filename
-            )))?;
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let filename = match parse_entry(file.name()) {
+            Some(ArchiveEntry::CachedFile(id, index, extension)) => match restored_ids.get(&id) {
+                None => continue,
+                Some(new_id) => cache::cache_directory(username).join(format!(
+                    "{}.{}",
+                    cache::url_basename(new_id, index),
+                    extension
+                )),
+            },
+            Some(ArchiveEntry::SnapshotFile(id, filename)) => match restored_ids.get(&id) {
+                None => continue,
+                Some(new_id) => snapshots::snapshot_directory(username, new_id).join(filename),
+            },
+            _ => continue,
+        };
 
-            zip.start_file(format!("recense_user_archive/{}", filename), file_options)?;
-            zip.write_all(&bytes)?;
+        if let Some(directory) = filename.parent() {
+            std::fs::create_dir_all(directory)?;
         }
-        zip.finish()?;
+        std::io::copy(
+            &mut (&mut file).take(MAX_ENTRY_SIZE),
+            &mut std::fs::File::create(filename)?,
+        )?;
+        summary.cached_files += 1;
+    }
+
+    summary.restored = pins.len();
+    storage.add_restored_pins(username, pins)?;
+
+    // Tags keep their capture intervals, unless the user set others already
+    if let Some(tag_intervals) = tag_intervals {
+        let current = snapshots::get_tag_intervals(username)?;
+        if mode == RestoreMode::Replace {
+            for tag in current.keys() {
+                if !tag_intervals.contains_key(tag) {
+                    snapshots::set_tag_interval(username, tag, 0)?;
+                }
+            }
+        }
+        for (tag, days) in tag_intervals {
+            if mode == RestoreMode::Replace || !current.contains_key(&tag) {
+                snapshots::set_tag_interval(username, &tag, days)?;
+            }
+        }
+    }
+
+    // Merged pins keep leading to the pin they were merged into, under its new id if it got one
+    if let Some(redirects) = redirects {
+        let mut current = match mode {
+            RestoreMode::Replace => HashMap::new(),
+            RestoreMode::Merge => pin_redirects::get_redirects(username)?,
+        };
+        for (from, to) in redirects {
+            let to = match restored_ids.get(&to) {
+                Some(id) => id.clone(),
+                None if kept_ids.contains(&to) => to,
+                None => continue,
+            };
+            current.entry(from).or_insert(to);
+        }
+        pin_redirects::save_redirects(username, &current)?;
+    }
+
+    if mode == RestoreMode::Replace {
+        for id in existing.keys() {
+            if !kept_ids.contains(id) {
+                storage.delete_pin(username, id)?;
+                summary.trashed += 1;
+            }
+        }
+    }
+
+    info!(
+        "Restored {} pins for {} from an archive, {} unchanged, {} renamed, {} trashed",
+        summary.restored, username, summary.unchanged, summary.renamed, summary.trashed
+    );
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fake_storage;
+
+    fn new_pin(url: &str) -> Pin {
+        let mut pin = Pin::new();
+        pin.urls = vec![url.into()];
+        pin
+    }
+
+    #[test]
+    fn restored_archive_keeps_revisions_and_redirects() {
+        let storage = fake_storage();
+        let username = "archived";
+        UserInfo::new_user(username.into(), "a@b.c".into(), "password123".into()).unwrap();
+
+        let mut edited = new_pin("http://127.0.0.1:9/edited");
+        storage.add_pin(username.into(), edited.clone()).unwrap();
+        edited.title = "Edited".into();
+        storage.add_pin(username.into(), edited.clone()).unwrap();
+
+        let kept = new_pin("http://127.0.0.1:9/kept");
+        let gone = new_pin("http://127.0.0.1:9/gone");
+        storage.add_pin(username.into(), kept.clone()).unwrap();
+        storage.add_pin(username.into(), gone.clone()).unwrap();
+        let merged = storage
+            .merge_pins(username, &[kept.id.clone(), gone.id.clone()])
+            .unwrap();
+
+        let archive: Vec<u8> = generate_archive_for_user(&storage, username, false)
+            .unwrap()
+            .wait()
+            .map(|chunk| chunk.unwrap().to_vec())
+            .collect::<Vec<_>>()
+            .concat();
+
+        // Into an empty account, everything comes back as it was
+        let copy = "archived_copy";
+        let summary = restore_archive(&storage, copy, &archive, RestoreMode::Merge).unwrap();
+        assert_eq!(summary.restored, 2);
+        let titles = |username: &str| -> Vec<String> {
+            let revisions = storage.get_pin_revisions(username, &edited.id).unwrap();
+            revisions.into_iter().map(|(_, pin)| pin.title).collect()
+        };
+        assert_eq!(titles(copy), titles(username));
+        assert_eq!(titles(copy).first().unwrap(), "");
+        assert_eq!(titles(copy).last().unwrap(), "Edited");
+        assert_eq!(storage.merged_into(copy, &gone.id), Some(merged.id.clone()));
+
+        // A pin whose id is taken by another one gets a new id, and redirects follow it
+        let taken = "archived_taken";
+        let mut other = new_pin("http://127.0.0.1:9/other");
+        other.id = merged.id.clone();
+        storage.add_pin(taken.into(), other).unwrap();
+        let summary = restore_archive(&storage, taken, &archive, RestoreMode::Merge).unwrap();
+        assert_eq!(summary.renamed, 1);
+        let renamed = storage.merged_into(taken, &gone.id).unwrap();
+        assert_ne!(renamed, merged.id);
+        assert_eq!(storage.get_pin(taken, &renamed).unwrap().urls, merged.urls);
     }
-    Ok(buf)
 }
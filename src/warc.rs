@@ -0,0 +1,290 @@
+use chrono::prelude::*;
+use failure::Error;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::http_client::{HttpClient, HttpResponse};
+
+/// A response received while downloading a page, along with when it was received
+pub struct Capture {
+    pub date: DateTime<Utc>,
+    pub response: HttpResponse,
+}
+
+/// Redirects followed from a single url before giving up
+const MAX_REDIRECTS: usize = 10;
+
+/// Passes requests on to another client, keeping every response so they can be written to a
+/// WARC file afterwards. Redirects are followed here rather than by the other client, so every
+/// hop gets its own record.
+///
+/// Only requests made by recense itself go through it, not the browser's: the page's own record
+/// comes from requesting it once more after the browser is done, which may get a different
+/// response than the browser did, like for pages that change on every visit.
+pub struct RecordingClient {
+    inner: Arc<dyn HttpClient>,
+    captures: Mutex<Vec<Capture>>,
+}
+
+impl RecordingClient {
+    pub fn new(inner: Arc<dyn HttpClient>) -> RecordingClient {
+        RecordingClient {
+            inner,
+            captures: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn into_captures(self) -> Vec<Capture> {
+        self.captures.into_inner().unwrap()
+    }
+}
+
+impl HttpClient for RecordingClient {
+    fn get(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error> {
+        let mut url = url.to_string();
+
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self.get_single(&url, max_size)?;
+            match redirect_location(&response) {
+                None => return Ok(response),
+                Some(location) => url = location,
+            }
+        }
+
+        bail!("Too many redirects, gave up at {}", url)
+    }
+
+    fn get_single(&self, url: &str, max_size: usize) -> Result<HttpResponse, Error> {
+        let response = self.inner.get_single(url, max_size)?;
+
+        self.captures.lock().unwrap().push(Capture {
+            date: Utc::now(),
+            response: response.clone(),
+        });
+
+        Ok(response)
+    }
+
+    /// HEAD requests aren't part of what gets archived, so they're not recorded
+    fn head(&self, url: &str) -> Result<HttpResponse, Error> {
+        self.inner.head(url)
+    }
+}
+
+/// Where a response redirects to, if it's a redirect
+fn redirect_location(response: &HttpResponse) -> Option<String> {
+    if !(300..400).contains(&response.status) {
+        return None;
+    }
+
+    let location = response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value)?;
+
+    url::Url::parse(&response.url)
+        .and_then(|url| url.join(location))
+        .ok()
+        .map(|url| url.to_string())
+}
+
+/// Builds a record id that's unique enough, formatted as a urn:uuid
+fn record_id(seed: &str) -> String {
+    let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let hash = sha1::Sha1::from(format!("{}{}", seed, nanos)).hexdigest();
+
+    format!(
+        "<urn:uuid:{}-{}-{}-{}-{}>",
+        &hash[0..8],
+        &hash[8..12],
+        &hash[12..16],
+        &hash[16..20],
+        &hash[20..32]
+    )
+}
+
+fn write_record(
+    out: &mut dyn Write,
+    headers: &[(&str, String)],
+    content_type: &str,
+    block: &[u8],
+) -> Result<(), Error> {
+    write!(out, "WARC/1.1\r\n")?;
+    for (name, value) in headers {
+        write!(out, "{}: {}\r\n", name, value)?;
+    }
+    write!(out, "Content-Type: {}\r\n", content_type)?;
+    write!(out, "Content-Length: {}\r\n\r\n", block.len())?;
+    out.write_all(block)?;
+    write!(out, "\r\n\r\n")?;
+    Ok(())
+}
+
+/// Rebuilds the request that was sent for a capture. The http client doesn't expose the exact
+/// bytes it sent, so this only has the headers that matter for replaying.
+fn request_message(response: &HttpResponse) -> Vec<u8> {
+    let (path, host) = match url::Url::parse(&response.url) {
+        Err(_) => (String::from("/"), String::new()),
+        Ok(url) => {
+            let mut path = url.path().to_string();
+            if let Some(query) = url.query() {
+                path = format!("{}?{}", path, query);
+            }
+            let host = match url.port() {
+                None => url.host_str().unwrap_or_default().to_string(),
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            };
+            (path, host)
+        }
+    };
+
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: recense/{}\r\nAccept-Encoding: gzip\r\n\r\n",
+        path,
+        host,
+        env!("CARGO_PKG_VERSION")
+    )
+    .into_bytes()
+}
+
+/// Rebuilds the http response message of a capture. As the body was already decoded, headers
+/// describing its encoding on the wire are dropped and its length is fixed up.
+fn response_message(response: &HttpResponse) -> Vec<u8> {
+    let mut message = format!(
+        "{} {} {}\r\n",
+        response.http_version, response.status, response.status_text
+    );
+
+    for (name, value) in &response.headers {
+        let name_lowercase = name.to_lowercase();
+        if name_lowercase == "content-encoding"
+            || name_lowercase == "transfer-encoding"
+            || name_lowercase == "content-length"
+        {
+            continue;
+        }
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str(&format!("Content-Length: {}\r\n\r\n", response.body.len()));
+
+    let mut message = message.into_bytes();
+    message.extend_from_slice(&response.body);
+    message
+}
+
+/// Writes a WARC file with a request and response record for every capture
+pub fn write_warc(filename: &Path, captures: &[Capture]) -> Result<(), Error> {
+    let mut out = vec![];
+
+    let warcinfo = format!(
+        "software: recense/{}\r\nformat: WARC File Format 1.1\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    write_record(
+        &mut out,
+        &[
+            ("WARC-Type", String::from("warcinfo")),
+            ("WARC-Record-ID", record_id("warcinfo")),
+            (
+                "WARC-Date",
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ),
+            (
+                "WARC-Filename",
+                filename
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+        ],
+        "application/warc-fields",
+        warcinfo.as_bytes(),
+    )?;
+
+    for (i, capture) in captures.iter().enumerate() {
+        let date = capture.date.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let response_id = record_id(&format!("response{}{}", i, capture.response.url));
+        let request_id = record_id(&format!("request{}{}", i, capture.response.url));
+
+        write_record(
+            &mut out,
+            &[
+                ("WARC-Type", String::from("response")),
+                ("WARC-Record-ID", response_id.clone()),
+                ("WARC-Date", date.clone()),
+                ("WARC-Target-URI", capture.response.url.clone()),
+            ],
+            "application/http;msgtype=response",
+            &response_message(&capture.response),
+        )?;
+
+        write_record(
+            &mut out,
+            &[
+                ("WARC-Type", String::from("request")),
+                ("WARC-Record-ID", request_id),
+                ("WARC-Date", date),
+                ("WARC-Target-URI", capture.response.url.clone()),
+                ("WARC-Concurrent-To", response_id),
+            ],
+            "application/http;msgtype=request",
+            &request_message(&capture.response),
+        )?;
+    }
+
+    std::fs::write(filename, out)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{response, FakeHttpClient};
+
+    #[test]
+    fn every_redirect_is_recorded() {
+        let client = Arc::new(FakeHttpClient::new());
+        let mut moved = response("http://example.com/old", 301, "text/html", b"");
+        moved.headers = vec![("Location".into(), "/new".into())];
+        client.respond("GET", "http://example.com/old", moved);
+        client.respond(
+            "GET",
+            "http://example.com/new",
+            response("http://example.com/new", 200, "text/html", b"page"),
+        );
+
+        let recorder = RecordingClient::new(client);
+        let page = recorder.get("http://example.com/old", 1024).unwrap();
+        assert_eq!(page.url, "http://example.com/new");
+        assert_eq!(page.body, b"page");
+
+        let captures: Vec<(String, u16)> = recorder
+            .into_captures()
+            .into_iter()
+            .map(|c| (c.response.url, c.response.status))
+            .collect();
+        assert_eq!(
+            captures,
+            vec![
+                ("http://example.com/old".to_string(), 301),
+                ("http://example.com/new".to_string(), 200)
+            ]
+        );
+    }
+
+    #[test]
+    fn redirect_loops_give_up() {
+        let client = Arc::new(FakeHttpClient::new());
+        let mut moved = response("http://example.com/loop", 302, "", b"");
+        moved.headers = vec![("location".into(), "http://example.com/loop".into())];
+        client.respond("GET", "http://example.com/loop", moved);
+
+        let recorder = RecordingClient::new(client);
+        assert!(recorder.get("http://example.com/loop", 1024).is_err());
+        assert_eq!(recorder.into_captures().len(), MAX_REDIRECTS + 1);
+    }
+}